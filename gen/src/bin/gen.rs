@@ -1,7 +1,15 @@
-use std::{collections::HashSet, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
-use generator::feed::FeedConfig;
+use generator::{
+    feed::FeedConfig,
+    post::{Assets, Post, Site},
+};
 use notify_debouncer_full::notify;
 use ttf_parser::Tag;
 
@@ -31,17 +39,125 @@ struct Args {
     #[arg(long, requires = "feed")]
     feed_cfg: Option<PathBuf>,
 
+    /// Feed of edits to existing posts, with the diff of each revision
+    #[arg(long, requires = "feed_cfg")]
+    updates_feed: Option<PathBuf>,
+
+    /// Number of most recent edits in the updates feed
+    #[arg(long, default_value = "20")]
+    updates_feed_len: usize,
+
     /// Font subset output
     #[arg(long)]
     subset_font: Option<PathBuf>,
 
+    /// Site-wide configuration
+    #[arg(long)]
+    site_cfg: Option<PathBuf>,
+
+    /// Glossary output, listing abbreviations and the posts using them
+    #[arg(long)]
+    glossary: Option<PathBuf>,
+
+    /// Series output, listing the parts of each series
+    #[arg(long)]
+    series: Option<PathBuf>,
+
+    /// Tag output, listing registered and used tags with post statistics
+    #[arg(long)]
+    tags: Option<PathBuf>,
+
+    /// Output directory for local images referenced by posts
+    #[arg(long)]
+    assets: Option<PathBuf>,
+
+    /// URL prefix under which the asset directory is served
+    #[arg(long, default_value = "/assets")]
+    assets_url: String,
+
+    /// Keep EXIF metadata (e.g. GPS location) in published images
+    #[arg(long)]
+    keep_exif: bool,
+
     /// Feed summary target length in bytes
     #[arg(long, default_value = "200")]
     feed_summary_len: usize,
 
+    /// Include draft posts
+    #[arg(long)]
+    drafts: bool,
+
+    /// Timestamp lockfile, used if the git history is unavailable (e.g. in shallow clones)
+    #[arg(long)]
+    timestamps: Option<PathBuf>,
+
+    /// Write the timestamp lockfile from the git history, failing if it is unavailable
+    #[arg(long, requires = "timestamps", conflicts_with = "verify")]
+    write_timestamps: bool,
+
     /// Watch mode
     #[arg(short, long)]
     watch: bool,
+
+    /// Check that regenerated outputs and assets are identical to those on disk, without writing
+    #[arg(long, conflicts_with = "watch")]
+    verify: bool,
+}
+
+/// Write an output unless it is unchanged. With `verify`, differing outputs are
+/// collected instead of written.
+fn emit(
+    path: &Path,
+    content: &[u8],
+    verify: bool,
+    differing: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let unchanged = std::fs::read(path).is_ok_and(|existing| existing == content);
+    if unchanged {
+        log::debug!("Unchanged: {}", path.display());
+    } else if verify {
+        log::error!("Output differs: {}", path.display());
+        differing.push(path.to_owned());
+    } else {
+        std::fs::write(path, content)?;
+    }
+    Ok(())
+}
+
+/// Canonicalize a path which may have been removed, in which case only its parent is resolved.
+fn canonical(path: &Path) -> Option<PathBuf> {
+    std::fs::canonicalize(path).ok().or_else(|| {
+        let parent = std::fs::canonicalize(path.parent()?).ok()?;
+        Some(parent.join(path.file_name()?))
+    })
+}
+
+/// Map changed paths to the posts that need to be refreshed.
+///
+/// Posts are kept as-is, while other files are replaced by all posts depending on them.
+fn affected_posts(
+    posts_dir: &Path,
+    posts: &HashMap<String, Post>,
+    paths: HashSet<PathBuf>,
+) -> HashSet<PathBuf> {
+    let mut affected = HashSet::new();
+    for path in paths {
+        if path.extension().is_some_and(|ext| ext == "md") {
+            affected.insert(path);
+            continue;
+        }
+
+        let Some(path) = canonical(&path) else {
+            continue;
+        };
+        for (filename, post) in posts {
+            if post.deps.contains(&path) {
+                log::info!("Dependency {} changed", path.display());
+                affected.insert(posts_dir.join(filename));
+            }
+        }
+    }
+    affected
 }
 
 fn main() -> anyhow::Result<()> {
@@ -55,6 +171,21 @@ fn main() -> anyhow::Result<()> {
         None
     };
 
+    let mut site = if let Some(ref p) = args.site_cfg {
+        Site::load(p)?
+    } else {
+        Site::default()
+    };
+    site.drafts = args.drafts;
+    site.timestamps = args.timestamps.clone();
+    site.write_timestamps = args.write_timestamps;
+    site.assets = args.assets.as_ref().map(|dir| Assets {
+        dir: dir.clone(),
+        url: args.assets_url.clone(),
+        keep_exif: args.keep_exif,
+        verify: args.verify,
+    });
+
     log::info!("Loading font from {}", args.title_font.display());
     let mut font_file = File::open(&args.title_font)?;
     let mut font_buf = Vec::new();
@@ -71,10 +202,10 @@ fn main() -> anyhow::Result<()> {
     }
 
     log::info!("Loading posts from {}", args.posts.display());
-    let mut posts = generator::post::readdir(&args.posts, &font)?;
+    let mut posts = generator::post::readdir(&args.posts, &site, &font)?;
 
     // Enable watch mode
-    let watch_rx = if args.watch {
+    let mut watch_rx = if args.watch {
         log::info!("Enable watch mode");
         let (tx, rx) = std::sync::mpsc::channel();
         let mut watcher =
@@ -87,13 +218,47 @@ fn main() -> anyhow::Result<()> {
     } else {
         None
     };
+    let posts_dir = std::fs::canonicalize(&args.posts)?;
+    let mut watched_dirs = HashSet::new();
 
     loop {
+        site.link_series(&mut posts);
+        site.link_collation(&mut posts);
+        generator::post::link_related(&mut posts);
         let mut posts_vec: Vec<_> = posts.values().collect();
-        posts_vec.sort_by(|a, b| b.metadata.publish_time.cmp(&a.metadata.publish_time));
+        posts_vec.sort_by(|a, b| {
+            b.metadata
+                .publish_time
+                .cmp(&a.metadata.publish_time)
+                .then_with(|| a.metadata.id.cmp(&b.metadata.id))
+                .then_with(|| a.metadata.lang.cmp(&b.metadata.lang))
+        });
+        let mut differing = Vec::new();
 
         log::debug!("Writing to: {}", args.output.display());
-        serde_json::to_writer(std::fs::File::create(&args.output)?, &posts_vec)?;
+        let data = serde_json::to_vec(&posts_vec)?;
+        emit(&args.output, &data, args.verify, &mut differing)?;
+
+        if let Some(ref g) = args.glossary {
+            log::debug!("Writing glossary to: {}", g.display());
+            let glossary = site.glossary(posts_vec.iter().copied());
+            let data = serde_json::to_vec(&glossary)?;
+            emit(g, &data, args.verify, &mut differing)?;
+        }
+
+        if let Some(ref s) = args.series {
+            log::debug!("Writing series to: {}", s.display());
+            let series = site.series(posts_vec.iter().copied());
+            let data = serde_json::to_vec(&series)?;
+            emit(s, &data, args.verify, &mut differing)?;
+        }
+
+        if let Some(ref t) = args.tags {
+            log::debug!("Writing tags to: {}", t.display());
+            let tags = site.tags(posts_vec.iter().copied());
+            let data = serde_json::to_vec(&tags)?;
+            emit(t, &data, args.verify, &mut differing)?;
+        }
 
         if let Some(ref f) = feed_cfg {
             let dst = args.feed.as_ref().unwrap();
@@ -101,31 +266,76 @@ fn main() -> anyhow::Result<()> {
 
             let feed =
                 generator::feed::feed(&f, posts_vec.iter().map(|e| *e), args.feed_summary_len)?;
-            feed.write_to(File::create(dst)?)?;
+            let data = feed.write_to(Vec::new())?;
+            emit(dst, &data, args.verify, &mut differing)?;
+
+            if let Some(ref dst) = args.updates_feed {
+                log::info!("Generating updates feed to: {}", dst.display());
+                let repo = generator::post::open_history(&args.posts)
+                    .map_err(|e| anyhow::anyhow!("Updates feed requires the git history: {}", e))?;
+                let feed = generator::feed::updates_feed(
+                    &f,
+                    posts_vec.iter().copied(),
+                    args.updates_feed_len,
+                    &repo,
+                )?;
+                let data = feed.write_to(Vec::new())?;
+                emit(dst, &data, args.verify, &mut differing)?;
+            }
         }
 
-        // TODO: check if subset changed
         if let Some(ref f) = args.subset_font {
             log::info!("Generating subset font to: {}", f.display());
+            // Subset into a temporary file first, so that an unchanged font is not rewritten
+            let tmp = tempfile::NamedTempFile::new()?;
             generator::font::generate_subset_to(
                 &args.title_font,
                 std::iter::once("分层")
-                    .chain(posts.values().map(|p| p.metadata.title.as_str()))
+                    .chain(posts_vec.iter().map(|p| p.metadata.title.as_str()))
                     .chain(
                         posts_vec
                             .iter()
                             .flat_map(|p| p.metadata.tags.iter().map(String::as_str)),
                     ),
-                f,
+                tmp.path(),
             )?;
+            let data = std::fs::read(tmp.path())?;
+            emit(f, &data, args.verify, &mut differing)?;
+        }
+
+        if !differing.is_empty() {
+            let paths: Vec<_> = differing.iter().map(|p| p.display().to_string()).collect();
+            return Err(anyhow::anyhow!(
+                "Regenerated outputs differ: {}",
+                paths.join(", ")
+            ));
+        }
+
+        // Printed regardless of log level, for setting up a rebuild
+        if let Some((filename, time)) = site.next_scheduled() {
+            println!(
+                "Next scheduled publication: {} ({})",
+                time.to_rfc3339(),
+                filename
+            );
         }
 
         if !args.watch {
             break Ok(());
         }
 
+        // Also watch for dependencies outside of the post directory
+        let (rx, watcher) = watch_rx.as_mut().unwrap();
+        for dep in posts.values().flat_map(|p| p.deps.iter()) {
+            let dir = dep.parent().unwrap();
+            if !dir.starts_with(&posts_dir) && watched_dirs.insert(dir.to_owned()) {
+                log::info!("Watching dependency directory {}", dir.display());
+                watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+            }
+        }
+
         loop {
-            let evs = watch_rx.as_ref().unwrap().0.recv()?.unwrap(); // TODO: Don't unwrap here!
+            let evs = rx.recv()?.unwrap(); // TODO: Don't unwrap here!
             let mut all_paths = HashSet::new();
             let mut has_update = false;
             for ev in evs.into_iter() {
@@ -151,8 +361,10 @@ fn main() -> anyhow::Result<()> {
                 continue;
             }
 
+            let all_paths = affected_posts(&args.posts, &posts, all_paths);
             let mut has_update = false;
-            let updates = generator::post::refresh_paths(&args.posts, all_paths.iter(), &font)?;
+            let updates =
+                generator::post::refresh_paths(&args.posts, all_paths.iter(), &site, &font)?;
             for (filename, post) in updates {
                 if let Some(post) = post {
                     log::info!("Update: {}", filename);
@@ -1,5 +1,5 @@
-use crate::post::Post;
-use atom_syndication::{Entry, EntryBuilder, Feed, Generator, LinkBuilder, Person, Text};
+use crate::post::{DT, Post, Revision};
+use atom_syndication::{Content, Entry, EntryBuilder, Feed, Generator, LinkBuilder, Person, Text};
 use serde::Deserialize;
 use url::Url;
 
@@ -11,23 +11,49 @@ pub struct FeedConfig {
     author: String,
 }
 
+/// Plaintext of the post, with spoilers replaced by a placeholder and the spoiler warning prepended
+fn spoiler_free_plain(post: &Post) -> String {
+    let zh = post.metadata.lang == "zh-CN";
+    let mut result = match &post.metadata.spoilers {
+        Some(warning) if zh => format!("剧透警告：{}\n\n", warning),
+        Some(warning) => format!("Spoilers: {}\n\n", warning),
+        None => String::new(),
+    };
+    let placeholder = if zh { "[剧透]" } else { "[spoiler]" };
+
+    // Spoiler ranges are in UTF-16 code units
+    let mut spoilers = post.spoilers.iter().peekable();
+    let mut offset = 0;
+    for c in post.plain.chars() {
+        while spoilers.next_if(|(_, end)| *end <= offset).is_some() {}
+        match spoilers.peek() {
+            Some((start, _)) if *start == offset => result += placeholder,
+            Some((start, _)) if *start < offset => {}
+            _ => result.push(c),
+        }
+        offset += c.len_utf16();
+    }
+    result
+}
+
 fn entry(cfg: &FeedConfig, post: &Post, summary_len: usize) -> anyhow::Result<Entry> {
     let uri = format!("{}/post/{}", cfg.base, post.metadata.id);
     let uri = Url::parse(&uri)?;
 
+    let plain = spoiler_free_plain(post);
     let mut summary_len = summary_len;
-    if summary_len > post.plain.len() {
-        summary_len = post.plain.len();
+    if summary_len > plain.len() {
+        summary_len = plain.len();
     } else {
-        while !post.plain.is_char_boundary(summary_len) {
+        while !plain.is_char_boundary(summary_len) {
             summary_len += 1;
         }
     }
 
-    let summary = if summary_len == post.plain.len() {
-        post.plain.to_owned()
+    let summary = if summary_len == plain.len() {
+        plain
     } else {
-        post.plain[..summary_len].to_owned() + "..."
+        plain[..summary_len].to_owned() + "..."
     };
 
     let entry = EntryBuilder::default()
@@ -52,21 +78,23 @@ fn entry(cfg: &FeedConfig, post: &Post, summary_len: usize) -> anyhow::Result<En
     Ok(entry)
 }
 
-pub fn feed<'a, I: Iterator<Item = &'a Post> + Clone>(
-    cfg: &FeedConfig,
-    posts: I,
-    summary_len: usize,
-) -> anyhow::Result<Feed> {
-    let latest_modification = posts
-        .clone()
+/// Last update of any listed post
+fn latest_modification<'a>(posts: impl Iterator<Item = &'a Post>) -> anyhow::Result<DT> {
+    posts
+        .filter(|p| p.metadata.visibility.is_listed())
         .map(|p| p.metadata.update_time.unwrap_or(p.metadata.publish_time))
         .max()
-        .unwrap();
-
-    let entries: Vec<_> = posts
-        .filter_map(|p| (!p.metadata.hidden).then(|| entry(cfg, p, summary_len)))
-        .try_collect()?;
+        .ok_or_else(|| anyhow::anyhow!("No listed posts to generate feed from"))
+}
 
+/// Assembles a feed published at `{base}/{name}`
+fn build(
+    cfg: &FeedConfig,
+    name: &str,
+    title: String,
+    updated: DT,
+    entries: Vec<Entry>,
+) -> anyhow::Result<Feed> {
     let generator = Generator {
         value: "Layered".to_owned(),
         uri: Some("https://github.com/CircuitCoder/layered".to_owned()),
@@ -74,13 +102,13 @@ pub fn feed<'a, I: Iterator<Item = &'a Post> + Clone>(
     };
 
     let base_uri = Url::parse(&cfg.base)?;
-    let feed_uri = format!("{}/feed.xml", base_uri);
+    let feed_uri = format!("{}/{}", base_uri, name);
     let feed_uri = Url::parse(&feed_uri)?;
 
     let feed = atom_syndication::FeedBuilder::default()
-        .id(format!("{}/feed.xml", cfg.base))
-        .title(cfg.title.clone())
-        .updated(latest_modification)
+        .id(format!("{}/{}", cfg.base, name))
+        .title(title)
+        .updated(updated)
         .author(Person {
             name: cfg.author.clone(),
             ..Person::default()
@@ -105,3 +133,108 @@ pub fn feed<'a, I: Iterator<Item = &'a Post> + Clone>(
 
     Ok(feed)
 }
+
+pub fn feed<'a, I: Iterator<Item = &'a Post> + Clone>(
+    cfg: &FeedConfig,
+    posts: I,
+    summary_len: usize,
+) -> anyhow::Result<Feed> {
+    let latest_modification = latest_modification(posts.clone())?;
+
+    let entries: Vec<_> = posts
+        .filter_map(|p| {
+            p.metadata
+                .visibility
+                .is_listed()
+                .then(|| entry(cfg, p, summary_len))
+        })
+        .try_collect()?;
+
+    build(
+        cfg,
+        "feed.xml",
+        cfg.title.clone(),
+        latest_modification,
+        entries,
+    )
+}
+
+fn update_entry(
+    cfg: &FeedConfig,
+    post: &Post,
+    rev: &Revision,
+    diff: String,
+) -> anyhow::Result<Entry> {
+    let uri = Url::parse(&format!("{}/post/{}", cfg.base, post.metadata.id))?;
+    let content = Content {
+        content_type: Some("html".to_owned()),
+        value: Some(diff),
+        ..Content::default()
+    };
+
+    // Language variants of a post share their URI, and may be edited in the same commit
+    let entry = EntryBuilder::default()
+        .id(format!(
+            "{}?rev={}&lang={}",
+            uri, rev.commit, post.metadata.lang
+        ))
+        .title(post.metadata.title.clone())
+        .updated(rev.time)
+        .link(
+            LinkBuilder::default()
+                .href(uri)
+                .rel("alternate".to_owned())
+                .build(),
+        )
+        .summary(Text::plain(rev.message.clone()))
+        .content(content)
+        .build();
+    Ok(entry)
+}
+
+/// Feed of edits to listed posts, with the most recent `limit` revisions changing their
+/// text, excluding the creation of each post. Entries are summarized by their commit
+/// message, and contain the diff of the post text read from `repo`.
+pub fn updates_feed<'a, I: Iterator<Item = &'a Post> + Clone>(
+    cfg: &FeedConfig,
+    posts: I,
+    limit: usize,
+    repo: &git2::Repository,
+) -> anyhow::Result<Feed> {
+    let mut edits: Vec<(&Post, &Revision)> = posts
+        .clone()
+        .filter(|p| p.metadata.visibility.is_listed())
+        .flat_map(|p| p.metadata.history.iter().map(move |r| (p, r)))
+        .filter(|(_, r)| !r.created)
+        .collect();
+    edits.sort_by(|(pa, a), (pb, b)| {
+        (b.time, &b.commit, &pb.metadata.lang).cmp(&(a.time, &a.commit, &pa.metadata.lang))
+    });
+
+    // Diffs are only rendered for emitted entries
+    let mut entries = Vec::new();
+    for (post, rev) in edits {
+        if entries.len() == limit {
+            break;
+        }
+        let diff = rev.diff(repo)?;
+        // e.g. renames
+        if diff.is_empty() {
+            continue;
+        }
+        entries.push(update_entry(cfg, post, rev, diff)?);
+    }
+
+    // Time of the newest entry, as edits are sorted newest first
+    let updated = match entries.first() {
+        Some(entry) => *entry.updated(),
+        None => latest_modification(posts)?,
+    };
+    build(
+        cfg,
+        "updates.xml",
+        format!("{} (updates)", cfg.title),
+        updated,
+        entries,
+    )
+}
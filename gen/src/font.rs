@@ -128,6 +128,17 @@ pub struct CharResp {
     pub hadv: u16,
 }
 
+/// Ruby annotation above `chars[start..end]` of a group
+#[derive(Serialize, Clone, Debug, ts_rs::TS)]
+#[ts(export)]
+pub struct RubyResp {
+    pub start: usize,
+    pub end: usize,
+    pub chars: Vec<CharResp>,
+    #[ts(type = "number")]
+    pub hadv: u64,
+}
+
 #[derive(Serialize, Clone, Debug, ts_rs::TS)]
 #[serde(rename_all = "camelCase")]
 #[ts(export)]
@@ -137,6 +148,7 @@ pub struct GroupResp {
     #[ts(type = "number")]
     pub hadv: u64,
     pub break_after: bool,
+    pub ruby: Vec<RubyResp>,
 }
 
 #[derive(Serialize, Clone, Debug, ts_rs::TS)]
@@ -413,9 +425,25 @@ pub fn parse_char(c: char, face: &ttf_parser::Face) -> anyhow::Result<CharResp>
 }
 
 pub fn parse_title(title: &str, face: &ttf_parser::Face) -> anyhow::Result<TitleResp> {
+    // Strip ruby annotations, recording (byte range in base text, reading)
+    let mut base = String::new();
+    let mut annotations = Vec::new();
+    for seg in crate::ruby::parse(title)? {
+        match seg {
+            crate::ruby::Segment::Text(t) => base.push_str(t),
+            crate::ruby::Segment::Ruby(pairs) => {
+                for (b, reading) in pairs {
+                    annotations.push((base.len()..base.len() + b.len(), reading));
+                    base.push_str(b);
+                }
+            }
+        }
+    }
+    let title = base.as_str();
+
     // Segmentation & line-break
     use unicode_segmentation::UnicodeSegmentation;
-    let segmented: BTreeMap<usize, bool> = title
+    let mut segmented: BTreeMap<usize, bool> = title
         .split_word_bound_indices()
         .map(|(i, _)| (i, false))
         .chain(unicode_linebreak::linebreaks(title).map(|(i, opp)| {
@@ -426,22 +454,45 @@ pub fn parse_title(title: &str, face: &ttf_parser::Face) -> anyhow::Result<Title
             (i, true)
         }))
         .collect();
+    // Annotated base text is never split
+    segmented.retain(|i, _| !annotations.iter().any(|(r, _)| r.start < *i && *i < r.end));
     let segs = segmented
         .iter()
         .tuple_windows()
-        .map(|((aptr, _), (bptr, bbp))| (&title[*aptr..*bptr], *bbp));
+        .map(|((aptr, _), (bptr, bbp))| (*aptr..*bptr, *bbp));
     let groups = segs
-        .map(|(s, break_after)| -> anyhow::Result<GroupResp> {
+        .map(|(range, break_after)| -> anyhow::Result<GroupResp> {
+            let s = &title[range.clone()];
             let chars = s
                 .chars()
                 .map(|c: char| parse_char(c, face))
                 .collect::<anyhow::Result<Vec<_>>>()?;
             let hadv = chars.iter().map(|c| c.hadv as u64).sum();
+
+            let char_idx = |byte: usize| title[range.start..byte].chars().count();
+            let ruby = annotations
+                .iter()
+                .filter(|(r, _)| range.start <= r.start && r.end <= range.end)
+                .map(|(r, reading)| -> anyhow::Result<RubyResp> {
+                    let chars = reading
+                        .chars()
+                        .map(|c: char| parse_char(c, face))
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    Ok(RubyResp {
+                        start: char_idx(r.start),
+                        end: char_idx(r.end),
+                        hadv: chars.iter().map(|c| c.hadv as u64).sum(),
+                        chars,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
             Ok(GroupResp {
                 chars,
                 text: s.to_string(),
                 break_after,
                 hadv,
+                ruby,
             })
         })
         .collect::<anyhow::Result<Vec<_>>>()?;
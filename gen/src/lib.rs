@@ -12,3 +12,4 @@ pub mod direction;
 pub mod feed;
 pub mod font;
 pub mod post;
+pub mod ruby;
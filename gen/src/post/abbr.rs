@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{Event, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use regex::Regex;
+use serde::Serialize;
+
+use super::Post;
+
+static DEFINITION_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\*\[([^\]]+)\]:\s*(.*)$").unwrap());
+
+/// Term to its expansion
+pub type Abbrs = HashMap<String, String>;
+
+/// Extract `*[TERM]: expansion` lines outside of fenced code blocks into `abbrs`.
+///
+/// Definitions are replaced by empty lines, so line numbers are kept intact. Definitions
+/// with a blank term are kept as text.
+pub fn extract(content: &str, abbrs: &mut Abbrs) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut fence: Option<&str> = None;
+
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        let fence_len = trimmed.len() - trimmed.trim_start_matches(['`', '~']).len();
+        if fence_len >= 3 {
+            let marker = &trimmed[..fence_len];
+            match fence {
+                None => fence = Some(marker),
+                Some(f) if marker.starts_with(f) && trimmed[fence_len..].trim().is_empty() => {
+                    fence = None
+                }
+                _ => {}
+            }
+        }
+
+        let definition = DEFINITION_RE
+            .captures(line)
+            .filter(|_| fence.is_none())
+            .map(|cap| {
+                (
+                    cap.get(1).unwrap().as_str().trim(),
+                    cap.get(2).unwrap().as_str(),
+                )
+            });
+        match definition {
+            Some(("", _)) => {
+                log::warn!("Ignoring abbreviation with an empty term: {}", line);
+                output.push_str(line);
+            }
+            Some((term, expansion)) => {
+                abbrs.insert(term.to_owned(), expansion.trim().to_owned());
+            }
+            None => output.push_str(line),
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Terms are matched as a whole, i.e. not adjacent to other ASCII alphanumerics.
+/// This still allows terms directly adjacent to CJK text.
+fn is_boundary(c: Option<char>) -> bool {
+    c.is_none_or(|c| !c.is_ascii_alphanumeric() && c != '_')
+}
+
+/// Merges definitions from a post into the site-wide glossary. Site-wide expansions take
+/// precedence, as in [`glossary`].
+pub fn merge(glossary: &Abbrs, post: Abbrs) -> Abbrs {
+    let mut merged = glossary.clone();
+    for (term, expansion) in post {
+        match merged.get(&term) {
+            Some(site) if *site != expansion => {
+                log::warn!("Ignoring redefinition of site-wide abbreviation {}", term)
+            }
+            Some(_) => {}
+            None => {
+                merged.insert(term, expansion);
+            }
+        }
+    }
+    merged
+}
+
+/// Wraps all occurrences of terms in `abbrs` into `<abbr>`, returning the events
+/// and the terms used. Code, links and image alt texts are left untouched.
+pub fn transform<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    abbrs: &Abbrs,
+) -> (Vec<(Event<'a>, Range<usize>)>, Vec<String>) {
+    if abbrs.is_empty() {
+        return (events, Vec::new());
+    }
+
+    // Prefer longer terms
+    let mut terms: Vec<_> = abbrs.keys().collect();
+    terms.sort_by_key(|t| std::cmp::Reverse(t.len()));
+    let pattern = terms
+        .iter()
+        .map(|t| regex::escape(t))
+        .collect::<Vec<_>>()
+        .join("|");
+    let re = Regex::new(&pattern).unwrap();
+
+    let mut output = Vec::with_capacity(events.len());
+    let mut used = BTreeSet::new();
+    let mut literal_depth = 0;
+
+    for (event, range) in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_) | Tag::Link { .. } | Tag::Image { .. }) => {
+                literal_depth += 1
+            }
+            Event::End(TagEnd::CodeBlock | TagEnd::Link | TagEnd::Image) => literal_depth -= 1,
+            Event::Text(ref s) if literal_depth == 0 => {
+                let mut last = 0;
+                let mut pos = 0;
+                while let Some(m) = re.find_at(s, pos) {
+                    // Empty terms are rejected by `extract`, but would match everywhere
+                    if m.is_empty()
+                        || !is_boundary(s[..m.start()].chars().next_back())
+                        || !is_boundary(s[m.end()..].chars().next())
+                    {
+                        let Some(c) = s[m.start()..].chars().next() else {
+                            break;
+                        };
+                        pos = m.start() + c.len_utf8();
+                        continue;
+                    }
+
+                    if last < m.start() {
+                        let before = s[last..m.start()].to_owned();
+                        output.push((Event::Text(before.into()), range.clone()));
+                    }
+                    let mut open = String::from("<abbr title=\"");
+                    escape_html(&mut open, &abbrs[m.as_str()]).unwrap();
+                    open.push_str("\">");
+                    output.push((Event::InlineHtml(open.into()), range.clone()));
+                    output.push((Event::Text(m.as_str().to_owned().into()), range.clone()));
+                    output.push((Event::InlineHtml("</abbr>".into()), range.clone()));
+                    used.insert(m.as_str().to_owned());
+                    last = m.end();
+                    pos = m.end();
+                }
+                if last == 0 {
+                    output.push((event, range));
+                } else if last < s.len() {
+                    let after = s[last..].to_owned();
+                    output.push((Event::Text(after.into()), range));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        output.push((event, range));
+    }
+
+    (output, used.into_iter().collect())
+}
+
+#[derive(Serialize, ts_rs::TS)]
+#[ts(export)]
+pub struct GlossaryEntry {
+    pub term: String,
+    pub expansion: String,
+    /// IDs of posts using this term
+    pub posts: Vec<String>,
+}
+
+/// Collects all terms used by listed posts, sorted by term.
+///
+/// Site-wide expansions take precedence. For terms only defined within posts, the
+/// expansion from the earliest post is used.
+pub fn glossary<'a>(glossary: &Abbrs, posts: impl Iterator<Item = &'a Post>) -> Vec<GlossaryEntry> {
+    let mut posts: Vec<_> = posts
+        .filter(|p| p.metadata.visibility.is_listed())
+        .collect();
+    posts.sort_by(|a, b| {
+        (a.metadata.publish_time, &a.metadata.id).cmp(&(b.metadata.publish_time, &b.metadata.id))
+    });
+
+    let mut entries: BTreeMap<&str, GlossaryEntry> = BTreeMap::new();
+    for post in posts {
+        for (term, expansion) in &post.abbrs {
+            let entry = entries.entry(term).or_insert_with(|| GlossaryEntry {
+                term: term.clone(),
+                expansion: glossary.get(term).unwrap_or(expansion).clone(),
+                posts: Vec::new(),
+            });
+            if !entry.posts.contains(&post.metadata.id) {
+                entry.posts.push(post.metadata.id.clone());
+            }
+        }
+    }
+    entries.into_values().collect()
+}
@@ -0,0 +1,245 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{BlockQuoteKind, Event, Tag, TagEnd};
+use regex::{Captures, Regex};
+
+use super::spoiler;
+
+/// Closes a callout title. The plaintext generator breaks lines here.
+pub const TITLE_ENDS: [&str; 2] = ["</p>\n", "</summary>\n"];
+
+static MARKER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\[!(\w+)\]([+-]?)[ \t]*").unwrap());
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+    Spoiler,
+}
+
+impl Kind {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "note" => Some(Kind::Note),
+            "tip" => Some(Kind::Tip),
+            "important" => Some(Kind::Important),
+            "warning" => Some(Kind::Warning),
+            "caution" => Some(Kind::Caution),
+            "spoiler" => Some(Kind::Spoiler),
+            _ => None,
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            Kind::Note => "note",
+            Kind::Tip => "tip",
+            Kind::Important => "important",
+            Kind::Warning => "warning",
+            Kind::Caution => "caution",
+            Kind::Spoiler => "spoiler",
+        }
+    }
+
+    fn label(self, lang: &str) -> &'static str {
+        match (self, lang) {
+            (Kind::Note, "zh-CN") => "备注",
+            (Kind::Tip, "zh-CN") => "提示",
+            (Kind::Important, "zh-CN") => "重要",
+            (Kind::Warning, "zh-CN") => "警告",
+            (Kind::Caution, "zh-CN") => "注意",
+            (Kind::Spoiler, "zh-CN") => "剧透",
+            (Kind::Note, _) => "Note",
+            (Kind::Tip, _) => "Tip",
+            (Kind::Important, _) => "Important",
+            (Kind::Warning, _) => "Warning",
+            (Kind::Caution, _) => "Caution",
+            (Kind::Spoiler, _) => "Spoiler",
+        }
+    }
+}
+
+impl From<BlockQuoteKind> for Kind {
+    fn from(kind: BlockQuoteKind) -> Self {
+        match kind {
+            BlockQuoteKind::Note => Kind::Note,
+            BlockQuoteKind::Tip => Kind::Tip,
+            BlockQuoteKind::Important => Kind::Important,
+            BlockQuoteKind::Warning => Kind::Warning,
+            BlockQuoteKind::Caution => Kind::Caution,
+        }
+    }
+}
+
+enum Fold {
+    None,
+    Collapsed,
+    Expanded,
+}
+
+/// Strips a `[!KIND]` marker matched by `re` and the rest of its line from the start of a
+/// blockquote, returning what `parse` makes of the marker and the rest of the line. These
+/// are not recognized by pulldown-cmark, which only accepts a bare marker on its own line.
+///
+/// Nothing is stripped if `parse` returns `None`.
+pub fn take_marker<'a, T>(
+    input: &mut VecDeque<Spanned<'a>>,
+    re: &Regex,
+    parse: impl FnOnce(&Captures) -> Option<T>,
+) -> Option<(T, Vec<Spanned<'a>>)> {
+    if !matches!(input.front(), Some((Event::Start(Tag::Paragraph), _))) {
+        return None;
+    }
+
+    // The marker is split into multiple text events by the link parser
+    let mut leading = String::new();
+    for (ev, _) in input.iter().skip(1) {
+        match ev {
+            Event::Text(s) => leading += s.as_ref(),
+            _ => break,
+        }
+    }
+    let cap = re.captures(&leading)?;
+    let parsed = parse(&cap)?;
+
+    let para_start = input.pop_front().unwrap();
+    let mut marker_len = cap.get(0).unwrap().len();
+    while marker_len > 0 {
+        let Some((Event::Text(s), range)) = input.pop_front() else {
+            unreachable!()
+        };
+        if s.len() > marker_len {
+            let rest = s[marker_len..].to_owned();
+            input.push_front((Event::Text(rest.into()), range));
+            break;
+        }
+        marker_len -= s.len();
+    }
+
+    // Rest of the first line
+    let mut title = Vec::new();
+    loop {
+        match input.front() {
+            Some((Event::SoftBreak, _)) => {
+                input.pop_front();
+                input.push_front(para_start);
+                break;
+            }
+            Some((Event::End(TagEnd::Paragraph), _)) => {
+                input.pop_front();
+                break;
+            }
+            Some(_) => title.push(input.pop_front().unwrap()),
+            None => break,
+        }
+    }
+    Some((parsed, title))
+}
+
+/// Parses a callout marker, with an optional fold indicator
+fn parse_marker(cap: &Captures) -> Option<(Kind, Fold)> {
+    let Some(kind) = Kind::parse(cap.get(1).unwrap().as_str()) else {
+        log::warn!("Unknown callout type: {}", cap.get(1).unwrap().as_str());
+        return None;
+    };
+    let fold = match cap.get(2).unwrap().as_str() {
+        "-" => Fold::Collapsed,
+        "+" => Fold::Expanded,
+        _ => Fold::None,
+    };
+    Some((kind, fold))
+}
+
+/// Renders GitHub-style callouts, i.e. blockquotes starting with `[!NOTE]`, `[!TIP]`,
+/// `[!IMPORTANT]`, `[!WARNING]` or `[!CAUTION]`.
+///
+/// A `-` or `+` right after the marker makes the callout collapsible, collapsed or expanded
+/// by default. The rest of the marker line, if any, replaces the localized title.
+///
+/// `[!SPOILER]` blocks are always collapsible, and their content is marked as spoiler.
+pub fn transform<'a>(events: Vec<Spanned<'a>>, lang: &str) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+    // Closing tags of enclosing blockquotes, None if rendered as-is
+    let mut stack: Vec<Option<&'static [&'static str]>> = Vec::new();
+
+    while let Some((event, range)) = input.pop_front() {
+        match event {
+            Event::Start(Tag::BlockQuote(gfm_kind)) => {
+                let callout = match gfm_kind {
+                    Some(kind) => Some((kind.into(), Fold::None, Vec::new())),
+                    None => take_marker(&mut input, &MARKER_RE, parse_marker)
+                        .map(|((kind, fold), title)| (kind, fold, title)),
+                };
+                let Some((kind, mut fold, title)) = callout else {
+                    stack.push(None);
+                    output.push((event, range));
+                    continue;
+                };
+
+                let spoiler = matches!(kind, Kind::Spoiler);
+                if spoiler && let Fold::None = fold {
+                    fold = Fold::Collapsed;
+                }
+
+                let (open, title_open, title_close, close): (_, _, _, &[&str]) = match fold {
+                    Fold::None => (
+                        format!("<aside class=\"callout callout-{}\">\n", kind.class()),
+                        "<p class=\"callout-title\">",
+                        TITLE_ENDS[0],
+                        &["</aside>\n"],
+                    ),
+                    Fold::Collapsed | Fold::Expanded => (
+                        format!(
+                            "<details class=\"callout callout-{}\"{}>\n",
+                            kind.class(),
+                            if let Fold::Expanded = fold {
+                                " open"
+                            } else {
+                                ""
+                            }
+                        ),
+                        "<summary class=\"callout-title\">",
+                        TITLE_ENDS[1],
+                        if spoiler {
+                            &[spoiler::BLOCK_END, "</details>\n"]
+                        } else {
+                            &["</details>\n"]
+                        },
+                    ),
+                };
+                output.push((Event::Html(open.into()), range.clone()));
+                output.push((Event::Html(title_open.into()), range.clone()));
+                if title.is_empty() {
+                    output.push((Event::Text(kind.label(lang).into()), range.clone()));
+                } else {
+                    output.extend(title);
+                }
+                output.push((Event::Html(title_close.into()), range.clone()));
+                if spoiler {
+                    output.push((Event::Html(spoiler::BLOCK_START.into()), range));
+                }
+                stack.push(Some(close));
+            }
+            Event::End(TagEnd::BlockQuote(_)) => match stack.pop().flatten() {
+                Some(close) => {
+                    for html in close {
+                        output.push((Event::Html((*html).into()), range.clone()));
+                    }
+                }
+                None => output.push((event, range)),
+            },
+            event => output.push((event, range)),
+        }
+    }
+
+    output
+}
@@ -0,0 +1,499 @@
+//! Static SVG charts rendered from `chart` code blocks.
+//!
+//! A block is either a YAML spec:
+//!
+//! ~~~text
+//! ```chart
+//! type: line
+//! title: Loss
+//! x_label: Epoch
+//! file: loss.csv
+//! ```
+//! ~~~
+//!
+//! or a chart type after the language, followed by CSV data:
+//!
+//! ~~~text
+//! ```chart bar
+//! component,PC1,PC2
+//! x,0.71,-0.12
+//! y,0.70,0.15
+//! ```
+//! ~~~
+//!
+//! The first CSV column is used as the x-axis, and every other column is a series. Texts
+//! inherit the font and color of the page, and series are colored with the site palette.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use serde::Deserialize;
+use unicode_width::UnicodeWidthStr;
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+/// Used if the site does not configure a palette
+const DEFAULT_PALETTE: [&str; 6] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#b07aa1",
+];
+
+const WIDTH: f64 = 640.;
+const HEIGHT: f64 = 360.;
+const FONT_SIZE: f64 = 12.;
+/// Rough width of a narrow character at `FONT_SIZE`
+const CHAR_W: f64 = 7.;
+const TICKS: f64 = 5.;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Line,
+    Bar,
+    Scatter,
+}
+
+impl Kind {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "line" => Ok(Kind::Line),
+            "bar" => Ok(Kind::Bar),
+            "scatter" => Ok(Kind::Scatter),
+            _ => Err(anyhow::anyhow!("Unknown chart type: {}", s)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    #[serde(rename = "type")]
+    kind: Kind,
+    title: Option<String>,
+    x_label: Option<String>,
+    y_label: Option<String>,
+    /// Inline CSV data
+    data: Option<String>,
+    /// CSV file, relative to the post
+    file: Option<String>,
+    /// Columns to plot, defaults to all columns but the first
+    series: Option<Vec<String>>,
+}
+
+pub fn is_chart(lang: &str) -> bool {
+    lang.split_whitespace().next() == Some("chart")
+}
+
+struct Table {
+    xs: Vec<String>,
+    series: Vec<(String, Vec<Option<f64>>)>,
+}
+
+fn parse_csv(data: &str, columns: Option<&[String]>) -> anyhow::Result<Table> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let headers = reader.headers()?.clone();
+    if headers.len() < 2 {
+        return Err(anyhow::anyhow!("Chart data needs at least two columns"));
+    }
+
+    let indices: Vec<usize> = match columns {
+        None => (1..headers.len()).collect(),
+        Some(columns) => columns
+            .iter()
+            .map(|c| {
+                headers
+                    .iter()
+                    .position(|h| h == c)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown column: {}", c))
+            })
+            .collect::<anyhow::Result<_>>()?,
+    };
+    let mut table = Table {
+        xs: Vec::new(),
+        series: indices
+            .iter()
+            .map(|&i| (headers[i].to_owned(), Vec::new()))
+            .collect(),
+    };
+
+    for record in reader.records() {
+        let record = record?;
+        table.xs.push(record.get(0).unwrap_or_default().to_owned());
+        for (&i, (name, values)) in indices.iter().zip(table.series.iter_mut()) {
+            let value = match record.get(i).unwrap_or_default() {
+                "" => None,
+                s => Some(
+                    s.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid value {:?} in column {}", s, name))?,
+                ),
+            };
+            values.push(value);
+        }
+    }
+    if table.xs.is_empty() {
+        return Err(anyhow::anyhow!("Chart data is empty"));
+    }
+    Ok(table)
+}
+
+/// Rounds `[min, max]` outwards to multiples of a 1-2-5 step, returning the bounds and the step.
+pub fn nice_range(min: f64, max: f64) -> (f64, f64, f64) {
+    let (min, max) = if min == max {
+        (min - 1., max + 1.)
+    } else {
+        (min, max)
+    };
+    let raw = (max - min) / TICKS;
+    let mag = 10f64.powf(raw.log10().floor());
+    let step = match raw / mag {
+        n if n < 1.5 => 1.,
+        n if n < 3. => 2.,
+        n if n < 7. => 5.,
+        _ => 10.,
+    } * mag;
+    (
+        (min / step).floor() * step,
+        (max / step).ceil() * step,
+        step,
+    )
+}
+
+pub fn format_tick(v: f64, step: f64) -> String {
+    let decimals = (-step.log10().floor()).max(0.) as usize;
+    let s = format!("{:.*}", decimals, v);
+    if s.trim_start_matches('-')
+        .chars()
+        .all(|c| c == '0' || c == '.')
+    {
+        // Avoid "-0"
+        s.trim_start_matches('-').to_owned()
+    } else {
+        s
+    }
+}
+
+/// Color of the `i`-th series
+pub fn series_color(palette: &[String], i: usize) -> String {
+    if palette.is_empty() {
+        DEFAULT_PALETTE[i % DEFAULT_PALETTE.len()].to_owned()
+    } else {
+        palette[i % palette.len()].clone()
+    }
+}
+
+/// Keeps the SVG compact
+pub fn round(v: f64) -> f64 {
+    (v * 100.).round() / 100.
+}
+
+pub fn text(out: &mut String, x: f64, y: f64, attrs: &str, s: &str) {
+    write!(out, "<text x=\"{}\" y=\"{}\" {}>", x, y, attrs).unwrap();
+    escape_html(&mut *out, s).unwrap();
+    out.push_str("</text>");
+}
+
+fn render(spec: Spec, table: Table, palette: &[String]) -> String {
+    let color = |i| series_color(palette, i);
+
+    let values = table.series.iter().flat_map(|(_, v)| v.iter().flatten());
+    let (mut min, mut max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+        (lo.min(v), hi.max(v))
+    });
+    if !min.is_finite() {
+        (min, max) = (0., 1.);
+    }
+    // Bars are always drawn from zero
+    if let Kind::Bar = spec.kind {
+        (min, max) = (min.min(0.), max.max(0.));
+    }
+    let (y_lo, y_hi, y_step) = nice_range(min, max);
+    let y_ticks: Vec<f64> = (0..=((y_hi - y_lo) / y_step).round() as usize)
+        .map(|i| y_lo + i as f64 * y_step)
+        .collect();
+
+    // Numeric x values are placed on a linear scale, otherwise in evenly spaced bands
+    let numeric_x: Option<Vec<f64>> = match spec.kind {
+        Kind::Bar => None,
+        _ => table.xs.iter().map(|x| x.parse().ok()).collect(),
+    };
+
+    let legend = table.series.len() > 1;
+    let tick_w = y_ticks
+        .iter()
+        .map(|&t| format_tick(t, y_step).chars().count())
+        .max()
+        .unwrap_or(0) as f64
+        * CHAR_W;
+    let left = 10. + tick_w + 8. + if spec.y_label.is_some() { 20. } else { 0. };
+    let right = WIDTH - 20.;
+    let top = 15. + if spec.title.is_some() { 25. } else { 0. } + if legend { 20. } else { 0. };
+    let bottom = HEIGHT - 25. - if spec.x_label.is_some() { 20. } else { 0. };
+
+    let sy = |v: f64| round(bottom - (v - y_lo) / (y_hi - y_lo) * (bottom - top));
+    let band = (right - left) / table.xs.len() as f64;
+    let (x_ticks, sx): (Vec<(f64, String)>, Vec<f64>) = match &numeric_x {
+        Some(xs) => {
+            let (min, max) = xs
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                    (lo.min(v), hi.max(v))
+                });
+            let (lo, hi, step) = nice_range(min, max);
+            let scale = move |v: f64| round(left + (v - lo) / (hi - lo) * (right - left));
+            let ticks = (0..=((hi - lo) / step).round() as usize)
+                .map(|i| lo + i as f64 * step)
+                .map(|t| (scale(t), format_tick(t, step)))
+                .collect();
+            (ticks, xs.iter().map(|&x| scale(x)).collect())
+        }
+        None => {
+            let center = move |i: usize| round(left + (i as f64 + 0.5) * band);
+            let ticks = table
+                .xs
+                .iter()
+                .enumerate()
+                .map(|(i, x)| (center(i), x.clone()))
+                .collect();
+            (ticks, (0..table.xs.len()).map(center).collect())
+        }
+    };
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" class=\"chart\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" role=\"img\" font-size=\"{f}\" fill=\"currentColor\">",
+        w = WIDTH,
+        h = HEIGHT,
+        f = FONT_SIZE
+    );
+    if let Some(title) = &spec.title {
+        svg.push_str("<title>");
+        escape_html(&mut svg, title).unwrap();
+        svg.push_str("</title>");
+        text(
+            &mut svg,
+            WIDTH / 2.,
+            20.,
+            "text-anchor=\"middle\" font-size=\"14\" font-weight=\"bold\"",
+            title,
+        );
+    }
+
+    // Grid and axes
+    svg.push_str("<g class=\"chart-grid\" stroke=\"currentColor\" stroke-opacity=\"0.15\">");
+    for &t in &y_ticks {
+        write!(
+            svg,
+            "<line x1=\"{}\" x2=\"{}\" y1=\"{y}\" y2=\"{y}\"/>",
+            left,
+            right,
+            y = sy(t)
+        )
+        .unwrap();
+    }
+    svg.push_str("</g>");
+    write!(
+        svg,
+        "<path class=\"chart-axis\" d=\"M{l} {t}L{l} {b}L{r} {b}\" fill=\"none\" stroke=\"currentColor\"/>",
+        l = left,
+        t = top,
+        b = bottom,
+        r = right
+    )
+    .unwrap();
+    for &t in &y_ticks {
+        text(
+            &mut svg,
+            left - 6.,
+            sy(t),
+            "text-anchor=\"end\" dominant-baseline=\"central\"",
+            &format_tick(t, y_step),
+        );
+    }
+    for (x, label) in &x_ticks {
+        text(&mut svg, *x, bottom + 16., "text-anchor=\"middle\"", label);
+    }
+    if let Some(label) = &spec.x_label {
+        text(
+            &mut svg,
+            (left + right) / 2.,
+            HEIGHT - 10.,
+            "text-anchor=\"middle\"",
+            label,
+        );
+    }
+    if let Some(label) = &spec.y_label {
+        let (x, y) = (15., (top + bottom) / 2.);
+        text(
+            &mut svg,
+            x,
+            y,
+            &format!(
+                "text-anchor=\"middle\" dominant-baseline=\"central\" transform=\"rotate(-90 {} {})\"",
+                x, y
+            ),
+            label,
+        );
+    }
+
+    // Series
+    let group = band * 0.8 / table.series.len() as f64;
+    for (si, (_, values)) in table.series.iter().enumerate() {
+        let c = color(si);
+        match spec.kind {
+            Kind::Line => {
+                let mut d = String::new();
+                let mut pen = false;
+                for (i, v) in values.iter().enumerate() {
+                    match v {
+                        Some(v) => {
+                            write!(d, "{}{} {}", if pen { "L" } else { "M" }, sx[i], sy(*v))
+                                .unwrap();
+                            pen = true;
+                        }
+                        // Missing values break the line
+                        None => pen = false,
+                    }
+                }
+                write!(
+                    svg,
+                    "<path d=\"{}\" fill=\"none\" style=\"stroke: {}\" stroke-width=\"2\" stroke-linejoin=\"round\" stroke-linecap=\"round\"/>",
+                    d, c
+                )
+                .unwrap();
+            }
+            Kind::Scatter => {
+                write!(svg, "<g style=\"fill: {}\">", c).unwrap();
+                for (i, v) in values.iter().enumerate() {
+                    if let Some(v) = v {
+                        write!(svg, "<circle cx=\"{}\" cy=\"{}\" r=\"3\"/>", sx[i], sy(*v))
+                            .unwrap();
+                    }
+                }
+                svg.push_str("</g>");
+            }
+            Kind::Bar => {
+                write!(svg, "<g style=\"fill: {}\">", c).unwrap();
+                for (i, v) in values.iter().enumerate() {
+                    let Some(v) = v else { continue };
+                    let x = left + i as f64 * band + band * 0.1 + si as f64 * group;
+                    let (y0, y1) = (sy(0f64.clamp(y_lo, y_hi)), sy(*v));
+                    write!(
+                        svg,
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+                        round(x),
+                        y0.min(y1),
+                        round(group),
+                        round((y0 - y1).abs())
+                    )
+                    .unwrap();
+                }
+                svg.push_str("</g>");
+            }
+        }
+    }
+
+    if legend {
+        let mut x = left;
+        let y = top - 20.;
+        for (si, (name, _)) in table.series.iter().enumerate() {
+            write!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"10\" height=\"10\" style=\"fill: {}\"/>",
+                x,
+                y - 5.,
+                color(si)
+            )
+            .unwrap();
+            text(&mut svg, x + 14., y, "dominant-baseline=\"central\"", name);
+            x += 14. + name.width() as f64 * CHAR_W + 16.;
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Parses a block with info string `info` and content `source`. CSV files are read with `load`.
+fn parse(
+    info: &str,
+    source: &str,
+    load: &impl Fn(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<(Spec, Table)> {
+    let spec = match info.split_whitespace().nth(1) {
+        Some(kind) => Spec {
+            kind: Kind::parse(kind)?,
+            title: None,
+            x_label: None,
+            y_label: None,
+            data: Some(source.to_owned()),
+            file: None,
+            series: None,
+        },
+        None => serde_yaml::from_str(source)?,
+    };
+    let data = match (&spec.data, &spec.file) {
+        (Some(data), None) => data.clone(),
+        (None, Some(file)) => load(file)?,
+        _ => return Err(anyhow::anyhow!("Chart needs exactly one of data or file")),
+    };
+    let table = parse_csv(&data, spec.series.as_deref())?;
+    Ok((spec, table))
+}
+
+/// Replaces `chart` code blocks with rendered SVGs. Blocks failing to render are reported
+/// and kept as code.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    palette: &[String],
+    load: impl Fn(&str) -> anyhow::Result<String>,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+
+    while let Some((event, range)) = input.pop_front() {
+        let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) = event else {
+            output.push((event, range));
+            continue;
+        };
+        if !is_chart(info) {
+            output.push((event, range));
+            continue;
+        }
+
+        let mut block = vec![(event.clone(), range.clone())];
+        let mut source = String::new();
+        while let Some((ev, r)) = input.pop_front() {
+            let end = matches!(ev, Event::End(TagEnd::CodeBlock));
+            if let Event::Text(ref s) = ev {
+                source.push_str(s);
+            }
+            block.push((ev, r));
+            if end {
+                break;
+            }
+        }
+
+        match parse(info, &source, &load) {
+            Ok((spec, table)) => {
+                let html = format!(
+                    "<div class=\"diagram diagram-chart\">{}</div>\n",
+                    render(spec, table, palette)
+                );
+                output.push((Event::Html(html.into()), range));
+            }
+            Err(e) => {
+                report(
+                    range.start,
+                    anyhow::anyhow!("Failed to render chart: {}", e),
+                );
+                output.extend(block);
+            }
+        }
+    }
+
+    output
+}
@@ -0,0 +1,307 @@
+//! `[@key]` citations resolved against BibTeX / BibLaTeX bibliographies, formatted with
+//! a CSL style by hayagriva.
+
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use hayagriva::archive::{ArchivedStyle, locales};
+use hayagriva::citationberg::{FontStyle, FontWeight, IndependentStyle, LocaleCode, Style};
+use hayagriva::{
+    BibliographyDriver, BibliographyRequest, CitationItem, CitationRequest, ElemChild,
+    ElemChildren, Entry, Library,
+};
+use pulldown_cmark::{CowStr, Event, HeadingLevel, LinkType, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use regex::Regex;
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+static CITE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[(@[^\s\[\];@]+(?:\s*;\s*@[^\s\[\];@]+)*)\]").unwrap());
+
+/// Style used if neither the site nor the post specifies one
+pub const DEFAULT_STYLE: &str = "ieee";
+
+/// Parses a BibTeX / BibLaTeX file
+pub fn parse_bib(source: &str) -> anyhow::Result<Library> {
+    hayagriva::io::from_biblatex_str(source).map_err(|errs| {
+        let errs: Vec<_> = errs.iter().map(ToString::to_string).collect();
+        anyhow::anyhow!("Invalid bibliography: {}", errs.join("; "))
+    })
+}
+
+/// Looks up a CSL style by its short name in the style archive shipped with hayagriva,
+/// e.g. `ieee`, `apa` or `chicago-author-date`
+pub fn style(name: &str) -> anyhow::Result<IndependentStyle> {
+    let archived = ArchivedStyle::by_name(name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown citation style: {}", name))?;
+    match archived.get() {
+        Style::Independent(s) => Ok(s),
+        Style::Dependent(_) => Err(anyhow::anyhow!(
+            "Citation style {} is a dependent style",
+            name
+        )),
+    }
+}
+
+fn anchor(key: &str) -> String {
+    let mut escaped = String::new();
+    escape_html(&mut escaped, &format!("ref-{}", key)).unwrap();
+    escaped
+}
+
+/// Converts formatted output of hayagriva into inline events, so that it is also
+/// included in the plaintext
+fn push_children(children: &ElemChildren, out: &mut Vec<Event<'static>>) {
+    for child in &children.0 {
+        match child {
+            ElemChild::Text(t) => {
+                let italic = t.formatting.font_style == FontStyle::Italic;
+                let bold = t.formatting.font_weight == FontWeight::Bold;
+                if italic {
+                    out.push(Event::Start(Tag::Emphasis));
+                }
+                if bold {
+                    out.push(Event::Start(Tag::Strong));
+                }
+                out.push(Event::Text(t.text.clone().into()));
+                if bold {
+                    out.push(Event::End(TagEnd::Strong));
+                }
+                if italic {
+                    out.push(Event::End(TagEnd::Emphasis));
+                }
+            }
+            ElemChild::Elem(e) => push_children(&e.children, out),
+            ElemChild::Markup(m) => out.push(Event::Text(m.clone().into())),
+            ElemChild::Link { text, url } => {
+                out.push(Event::Start(Tag::Link {
+                    link_type: LinkType::Inline,
+                    dest_url: url.clone().into(),
+                    title: CowStr::Borrowed(""),
+                    id: CowStr::Borrowed(""),
+                }));
+                out.push(Event::Text(text.text.clone().into()));
+                out.push(Event::End(TagEnd::Link));
+            }
+            ElemChild::Transparent { .. } => {}
+        }
+    }
+}
+
+fn has_link(children: &ElemChildren) -> bool {
+    children.0.iter().any(|c| match c {
+        ElemChild::Link { .. } => true,
+        ElemChild::Elem(e) => has_link(&e.children),
+        _ => false,
+    })
+}
+
+/// Link to the entry itself, preferring the DOI
+fn entry_link(entry: &Entry) -> Option<String> {
+    entry
+        .doi()
+        .map(|doi| format!("https://doi.org/{}", doi))
+        .or_else(|| entry.url_any().map(|u| u.value.to_string()))
+}
+
+/// Bibliographies a post cites from. Entries of the post shadow those of the site.
+pub struct Bibliography<'a> {
+    pub post: Option<Library>,
+    pub site: &'a Library,
+    pub style: IndependentStyle,
+}
+
+impl Bibliography<'_> {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.post
+            .as_ref()
+            .and_then(|l| l.get(key))
+            .or_else(|| self.site.get(key))
+    }
+}
+
+/// A citation group, as an index into the output and the keys cited
+struct Cite {
+    at: usize,
+    keys: Vec<String>,
+}
+
+/// Replaces `[@key]` and `[@key1; @key2]` with formatted citations linking to the
+/// bibliography, which is appended to the end of the post.
+///
+/// Citations within code, links and image alt texts are left untouched. Unknown keys
+/// are reported and the citation is kept as is.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    bib: &Bibliography,
+    lang: &str,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    // Adjacent text is merged first, since brackets are split into separate events
+    let mut output: Vec<Spanned<'a>> = Vec::with_capacity(events.len());
+    let mut cites = Vec::new();
+    let mut pending: Vec<Spanned<'a>> = Vec::new();
+    let mut literal_depth = 0;
+
+    let mut flush = |pending: &mut Vec<Spanned<'a>>, output: &mut Vec<Spanned<'a>>| {
+        if pending.is_empty() {
+            return;
+        }
+        let text: String = pending
+            .iter()
+            .map(|(ev, _)| match ev {
+                Event::Text(s) => s.as_ref(),
+                _ => unreachable!(),
+            })
+            .collect();
+        if !CITE_RE.is_match(&text) {
+            output.append(pending);
+            return;
+        }
+
+        let range = pending[0].1.start..pending.last().unwrap().1.end;
+        pending.clear();
+        let mut last = 0;
+        for cap in CITE_RE.captures_iter(&text) {
+            let whole = cap.get(0).unwrap();
+            let keys: Vec<_> = cap
+                .get(1)
+                .unwrap()
+                .as_str()
+                .split(';')
+                .map(|k| k.trim().trim_start_matches('@').to_owned())
+                .collect();
+            let unknown: Vec<_> = keys.iter().filter(|k| bib.get(k).is_none()).collect();
+            if !unknown.is_empty() {
+                for k in unknown {
+                    report(
+                        range.start,
+                        anyhow::anyhow!("Citation of unknown key {}", k),
+                    );
+                }
+                continue;
+            }
+
+            if last < whole.start() {
+                let before = text[last..whole.start()].to_owned();
+                output.push((Event::Text(before.into()), range.clone()));
+            }
+            cites.push(Cite {
+                at: output.len(),
+                keys,
+            });
+            // Placeholder, filled in after all citations are collected
+            output.push((Event::Text("".into()), range.clone()));
+            last = whole.end();
+        }
+        if last < text.len() {
+            let after = text[last..].to_owned();
+            output.push((Event::Text(after.into()), range));
+        }
+    };
+
+    for (event, range) in events {
+        match event {
+            Event::Text(_) if literal_depth == 0 => {
+                pending.push((event, range));
+                continue;
+            }
+            Event::Start(Tag::CodeBlock(_) | Tag::Link { .. } | Tag::Image { .. }) => {
+                literal_depth += 1
+            }
+            Event::End(TagEnd::CodeBlock | TagEnd::Link | TagEnd::Image) => literal_depth -= 1,
+            _ => {}
+        }
+        flush(&mut pending, &mut output);
+        output.push((event, range));
+    }
+    flush(&mut pending, &mut output);
+
+    if cites.is_empty() {
+        return output;
+    }
+
+    let locale_files = locales();
+    let locale = Some(LocaleCode(lang.to_owned()));
+    let mut driver = BibliographyDriver::new();
+    for cite in &cites {
+        let items = cite
+            .keys
+            .iter()
+            .map(|k| CitationItem::with_entry(bib.get(k).unwrap()))
+            .collect();
+        driver.citation(CitationRequest::new(
+            items,
+            &bib.style,
+            locale.clone(),
+            &locale_files,
+            None,
+        ));
+    }
+    let rendered = driver.finish(BibliographyRequest::new(&bib.style, locale, &locale_files));
+
+    // Splice citations in, from the back so that indices stay valid
+    for (cite, rendered) in cites.iter().zip(rendered.citations).rev() {
+        let range = output[cite.at].1.clone();
+        let open = format!("<a class=\"citation\" href=\"#{}\">", anchor(&cite.keys[0]));
+        let mut inner = vec![Event::InlineHtml(open.into())];
+        push_children(&rendered.citation, &mut inner);
+        inner.push(Event::InlineHtml("</a>".into()));
+        output.splice(
+            cite.at..cite.at + 1,
+            inner.into_iter().map(|ev| (ev, range.clone())),
+        );
+    }
+
+    let Some(bibliography) = rendered.bibliography else {
+        return output;
+    };
+    let end = output.last().map_or(0, |(_, r)| r.end);
+    let heading = match lang {
+        "zh-CN" => "参考文献",
+        _ => "References",
+    };
+    let mut section = vec![
+        Event::Html("<section class=\"bibliography\">\n".into()),
+        Event::Start(Tag::Heading {
+            level: HeadingLevel::H2,
+            id: Some("references".into()),
+            classes: Vec::new(),
+            attrs: Vec::new(),
+        }),
+        Event::Text(heading.into()),
+        Event::End(TagEnd::Heading(HeadingLevel::H2)),
+        Event::Start(Tag::List(None)),
+    ];
+    for item in bibliography.items {
+        section.push(Event::Start(Tag::Item));
+        let open = format!("<span class=\"bib-entry\" id=\"{}\">", anchor(&item.key));
+        section.push(Event::InlineHtml(open.into()));
+        if let Some(first) = item.first_field {
+            push_children(&ElemChildren(vec![first]), &mut section);
+            section.push(Event::Text(" ".into()));
+        }
+        push_children(&item.content, &mut section);
+        if !has_link(&item.content)
+            && let Some(url) = bib.get(&item.key).and_then(entry_link)
+        {
+            section.push(Event::Text(" ".into()));
+            section.push(Event::Start(Tag::Link {
+                link_type: LinkType::Autolink,
+                dest_url: url.clone().into(),
+                title: CowStr::Borrowed(""),
+                id: CowStr::Borrowed(""),
+            }));
+            section.push(Event::Text(url.into()));
+            section.push(Event::End(TagEnd::Link));
+        }
+        section.push(Event::InlineHtml("</span>".into()));
+        section.push(Event::End(TagEnd::Item));
+    }
+    section.push(Event::End(TagEnd::List(false)));
+    section.push(Event::Html("</section>\n".into()));
+    output.extend(section.into_iter().map(|ev| (ev, end..end)));
+
+    output
+}
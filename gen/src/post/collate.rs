@@ -0,0 +1,98 @@
+//! Collation keys, so that the frontend sorts titles and tags by plain string comparison.
+//!
+//! Texts are ordered with the CLDR collation of their language, i.e. by pinyin for
+//! Chinese and by the Unicode collation algorithm for Latin scripts. Japanese texts, i.e.
+//! those in `ja` or containing kana, are sorted by their ruby readings. Keys are ranks
+//! among all titles and tag names of the same language, so they are only comparable
+//! within a language and a single build.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use icu_collator::{Collator, CollatorOptions};
+use icu_locid::Locale;
+
+use super::Post;
+use super::tags::Registry;
+use crate::ruby;
+
+/// Collation keys by language, then by text
+pub type Keys = HashMap<String, HashMap<String, String>>;
+
+/// Whether a character is hiragana or katakana
+fn is_kana(c: char) -> bool {
+    ('\u{3040}'..='\u{30ff}').contains(&c)
+}
+
+/// Text a tag name or title is sorted by. Posts are only written in English or Chinese, so
+/// Japanese titles are recognized by their kana.
+pub fn sort_text(text: &str, lang: &str) -> String {
+    let converted = if lang.starts_with("ja") || text.chars().any(is_kana) {
+        ruby::reading_text(text)
+    } else {
+        ruby::base_text(text)
+    };
+    converted.unwrap_or_else(|_| text.to_owned())
+}
+
+fn collator(lang: &str) -> Option<Collator> {
+    let locale: Locale = lang
+        .parse()
+        .inspect_err(|e| log::warn!("Invalid language {}: {}", lang, e))
+        .ok()?;
+    Collator::try_new(&(&locale).into(), CollatorOptions::new())
+        .inspect_err(|e| log::warn!("No collation for {}: {}", lang, e))
+        .ok()
+}
+
+/// Computes keys for all post titles, and the names of all registered or used tags in
+/// every language seen.
+pub fn keys<'a>(registry: &Registry, posts: impl Iterator<Item = &'a Post>) -> Keys {
+    let posts: Vec<_> = posts.collect();
+    let mut texts: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    for post in &posts {
+        texts
+            .entry(&post.metadata.lang)
+            .or_default()
+            .insert(post.sort_title.clone());
+    }
+    for lang in registry.langs() {
+        texts.entry(lang).or_default();
+    }
+
+    let slugs: BTreeSet<&str> = registry
+        .slugs()
+        .chain(
+            posts
+                .iter()
+                .flat_map(|p| p.metadata.tags.iter().map(String::as_str)),
+        )
+        .collect();
+    for (lang, set) in &mut texts {
+        for slug in &slugs {
+            set.insert(sort_text(registry.name(slug, lang), lang));
+        }
+    }
+
+    texts
+        .into_iter()
+        .map(|(lang, set)| {
+            // Sets are sorted by code point, which breaks ties between equally collated texts
+            let mut sorted: Vec<_> = set.into_iter().collect();
+            if let Some(collator) = collator(lang) {
+                sorted.sort_by(|a, b| collator.compare(a, b));
+            }
+            let width = sorted.len().to_string().len();
+            let keys = sorted
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| (text, format!("{:0width$}", i, width = width)))
+                .collect();
+            (lang.to_owned(), keys)
+        })
+        .collect()
+}
+
+/// Looks up the key of a text already converted by [`sort_text`]
+pub fn key(keys: &Keys, lang: &str, sort_text: &str) -> String {
+    keys[lang][sort_text].clone()
+}
@@ -0,0 +1,263 @@
+//! Graphviz diagrams, laid out by layout-rs
+
+use std::fmt::Write;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use layout::core::color::Color;
+use layout::core::format::{ClipHandle, RenderBackend};
+use layout::core::geometry::Point;
+use layout::core::style::StyleAttr;
+use layout::gv::{DotParser, GraphBuilder};
+use pulldown_cmark_escape::escape_html;
+use unicode_width::UnicodeWidthStr;
+
+/// Collects drawing commands into SVG elements. Unlike the SVG backend shipped with
+/// layout-rs, the default black and white are mapped to the foreground and background
+/// colors of the page.
+struct Canvas<'a> {
+    id: &'a str,
+    content: String,
+    clips: Vec<String>,
+    size: Point,
+    arrows: usize,
+}
+
+impl Canvas<'_> {
+    fn grow(&mut self, xy: Point, size: Point) {
+        self.size.x = self.size.x.max(xy.x + size.x + 5.);
+        self.size.y = self.size.y.max(xy.y + size.y + 5.);
+    }
+
+    fn finalize(self) -> String {
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+            w = self.size.x,
+            h = self.size.y,
+        );
+        write!(
+            svg,
+            "<defs>\
+            <marker id=\"{id}-start\" markerWidth=\"10\" markerHeight=\"7\" refX=\"0\" refY=\"3.5\" orient=\"auto\">\
+            <polygon points=\"10 0, 10 7, 0 3.5\" fill=\"currentColor\"/></marker>\
+            <marker id=\"{id}-end\" markerWidth=\"10\" markerHeight=\"7\" refX=\"10\" refY=\"3.5\" orient=\"auto\">\
+            <polygon points=\"0 0, 10 3.5, 0 7\" fill=\"currentColor\"/></marker>",
+            id = self.id
+        )
+        .unwrap();
+        for clip in &self.clips {
+            svg.push_str(clip);
+        }
+        svg.push_str("</defs>");
+        svg.push_str(&self.content);
+        svg.push_str("</svg>");
+        svg
+    }
+}
+
+fn stroke(color: Color) -> String {
+    if color.to_web_color() == Color::fast("black").to_web_color() {
+        "stroke=\"currentColor\"".to_owned()
+    } else {
+        format!("stroke=\"{}\"", color.to_web_color())
+    }
+}
+
+fn fill(color: Option<Color>) -> String {
+    match color.map(|c| c.to_web_color()) {
+        None => "fill=\"none\"".to_owned(),
+        Some(c) if c == Color::fast("white").to_web_color() => {
+            "style=\"fill: var(--color-bg)\"".to_owned()
+        }
+        Some(c) if c == Color::fast("black").to_web_color() => "fill=\"currentColor\"".to_owned(),
+        Some(c) => format!("fill=\"{}\"", c),
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    escape_html(&mut escaped, s).unwrap();
+    escaped
+}
+
+impl RenderBackend for Canvas<'_> {
+    fn draw_rect(&mut self, xy: Point, size: Point, look: &StyleAttr, clip: Option<ClipHandle>) {
+        self.grow(xy, size);
+        write!(
+            self.content,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\" {} stroke-width=\"{}\" {}",
+            xy.x,
+            xy.y,
+            size.x,
+            size.y,
+            look.rounded,
+            fill(look.fill_color),
+            look.line_width,
+            stroke(look.line_color),
+        )
+        .unwrap();
+        if let Some(clip) = clip {
+            write!(
+                self.content,
+                " clip-path=\"url(#{}-clip-{})\"",
+                self.id, clip
+            )
+            .unwrap();
+        }
+        self.content.push_str("/>");
+    }
+
+    fn draw_line(&mut self, start: Point, stop: Point, look: &StyleAttr) {
+        write!(
+            self.content,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke-width=\"{}\" {}/>",
+            start.x,
+            start.y,
+            stop.x,
+            stop.y,
+            look.line_width,
+            stroke(look.line_color),
+        )
+        .unwrap();
+    }
+
+    fn draw_circle(&mut self, xy: Point, size: Point, look: &StyleAttr) {
+        self.grow(xy, size);
+        write!(
+            self.content,
+            "<ellipse cx=\"{}\" cy=\"{}\" rx=\"{}\" ry=\"{}\" {} stroke-width=\"{}\" {}/>",
+            xy.x,
+            xy.y,
+            size.x / 2.,
+            size.y / 2.,
+            fill(look.fill_color),
+            look.line_width,
+            stroke(look.line_color),
+        )
+        .unwrap();
+    }
+
+    fn draw_text(&mut self, xy: Point, text: &str, look: &StyleAttr) {
+        let lines = text.lines().count();
+        let height = ((lines + 1) * look.font_size) as f64;
+        // Text is centered at xy, with characters roughly 0.6em wide
+        let width =
+            text.lines().map(|l| l.width()).max().unwrap_or(0) as f64 * look.font_size as f64 * 0.6;
+        self.grow(xy, Point::new(width / 2., height / 2.));
+        write!(
+            self.content,
+            "<text x=\"{}\" y=\"{}\" font-size=\"{}\" text-anchor=\"middle\" dominant-baseline=\"middle\" fill=\"currentColor\">",
+            xy.x,
+            xy.y - height / 2.,
+            look.font_size,
+        )
+        .unwrap();
+        for line in text.lines() {
+            write!(
+                self.content,
+                "<tspan x=\"{}\" dy=\"1em\">{}</tspan>",
+                xy.x,
+                escape(line)
+            )
+            .unwrap();
+        }
+        self.content.push_str("</text>");
+    }
+
+    fn draw_arrow(
+        &mut self,
+        path: &[(Point, Point)],
+        dashed: bool,
+        head: (bool, bool),
+        look: &StyleAttr,
+        text: &str,
+    ) {
+        for (a, b) in path {
+            self.grow(*a, Point::zero());
+            self.grow(*b, Point::zero());
+        }
+
+        // The first pair is the exit vector, and the rest are entry vectors into the
+        // following points
+        let mut d = format!(
+            "M {} {} C {} {}, {} {}, {} {}",
+            path[0].0.x,
+            path[0].0.y,
+            path[0].1.x,
+            path[0].1.y,
+            path[1].0.x,
+            path[1].0.y,
+            path[1].1.x,
+            path[1].1.y
+        );
+        for (a, b) in path.iter().skip(2) {
+            write!(d, " S {} {}, {} {}", a.x, a.y, b.x, b.y).unwrap();
+        }
+
+        let id = format!("{}-arrow-{}", self.id, self.arrows);
+        self.arrows += 1;
+        write!(
+            self.content,
+            "<path id=\"{}\" d=\"{}\" fill=\"none\" stroke-width=\"{}\" {}",
+            id,
+            d,
+            look.line_width,
+            stroke(look.line_color),
+        )
+        .unwrap();
+        if dashed {
+            self.content.push_str(" stroke-dasharray=\"5,5\"");
+        }
+        if head.0 {
+            write!(self.content, " marker-start=\"url(#{}-start)\"", self.id).unwrap();
+        }
+        if head.1 {
+            write!(self.content, " marker-end=\"url(#{}-end)\"", self.id).unwrap();
+        }
+        self.content.push_str("/>");
+
+        if !text.is_empty() {
+            write!(
+                self.content,
+                "<text font-size=\"{}\" fill=\"currentColor\"><textPath href=\"#{}\" startOffset=\"50%\" text-anchor=\"middle\">{}</textPath></text>",
+                look.font_size,
+                id,
+                escape(text)
+            )
+            .unwrap();
+        }
+    }
+
+    fn create_clip(&mut self, xy: Point, size: Point, rounded_px: usize) -> ClipHandle {
+        let handle = self.clips.len();
+        self.clips.push(format!(
+            "<clipPath id=\"{}-clip-{}\"><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\"/></clipPath>",
+            self.id, handle, xy.x, xy.y, size.x, size.y, rounded_px
+        ));
+        handle
+    }
+}
+
+pub fn render(source: &str, id: &str) -> anyhow::Result<String> {
+    // The parser and layout engine panic on some malformed graphs
+    catch_unwind(AssertUnwindSafe(|| {
+        let mut parser = DotParser::new(source);
+        let graph = parser
+            .process()
+            .map_err(|e| anyhow::anyhow!("Syntax error: {}", e))?;
+
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut vg = builder.get();
+
+        let mut canvas = Canvas {
+            id,
+            content: String::new(),
+            clips: Vec::new(),
+            size: Point::zero(),
+            arrows: 0,
+        };
+        vg.do_it(false, false, false, &mut canvas);
+        Ok(canvas.finalize())
+    }))
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("Layout failed")))
+}
@@ -0,0 +1,84 @@
+//! Build-time rendering of diagram code blocks into inline SVG.
+//!
+//! All renderers draw with `currentColor` or CSS classes, so diagrams follow the page theme.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+
+mod dot;
+mod svgbob;
+mod wavedrom;
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+/// Languages of fenced code blocks rendered as diagrams
+pub fn is_diagram(lang: &str) -> bool {
+    matches!(lang, "dot" | "svgbob" | "wavedrom")
+}
+
+/// Renders `source` into an SVG. `id` prefixes all element IDs within the SVG, since
+/// multiple diagrams may be inlined into the same page.
+fn render(lang: &str, source: &str, id: &str) -> anyhow::Result<String> {
+    match lang {
+        "dot" => dot::render(source, id),
+        "svgbob" => svgbob::render(source, id),
+        "wavedrom" => wavedrom::render(source, id),
+        _ => unreachable!(),
+    }
+}
+
+/// Replaces fenced `dot`, `svgbob` and `wavedrom` code blocks with rendered SVGs.
+///
+/// The source is dropped, so it does not show up in the plaintext. Blocks failing to
+/// render are reported and kept as code.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+    let mut count = 0;
+
+    while let Some((event, range)) = input.pop_front() {
+        let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang))) = event else {
+            output.push((event, range));
+            continue;
+        };
+        if !is_diagram(lang) {
+            output.push((event, range));
+            continue;
+        }
+
+        let mut block = vec![(event.clone(), range.clone())];
+        let mut source = String::new();
+        while let Some((ev, r)) = input.pop_front() {
+            let end = matches!(ev, Event::End(TagEnd::CodeBlock));
+            if let Event::Text(ref s) = ev {
+                source.push_str(s);
+            }
+            block.push((ev, r));
+            if end {
+                break;
+            }
+        }
+
+        count += 1;
+        match render(lang, &source, &format!("diagram-{}", count)) {
+            Ok(svg) => {
+                let html = format!("<div class=\"diagram diagram-{}\">{}</div>\n", lang, svg);
+                output.push((Event::Html(html.into()), range));
+            }
+            Err(e) => {
+                report(
+                    range.start,
+                    anyhow::anyhow!("Failed to render {} diagram: {}", lang, e),
+                );
+                output.extend(block);
+            }
+        }
+    }
+
+    output
+}
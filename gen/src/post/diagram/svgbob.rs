@@ -0,0 +1,35 @@
+//! ASCII art diagrams, rendered by svgbob.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use svgbob::{CellBuffer, Node, Settings};
+
+static ID_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\bid="([^"]+)""#).unwrap());
+static REF_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"url\(#([^)]+)\)").unwrap());
+
+pub fn render(source: &str, id: &str) -> anyhow::Result<String> {
+    let settings = Settings {
+        font_family: "monospace".into(),
+        fill_color: "currentColor".into(),
+        stroke_color: "currentColor".into(),
+        // Fill of shapes covering lines, e.g. open circles
+        background: "var(--color-bg)".into(),
+        include_backdrop: false,
+        ..Settings::default()
+    };
+    let (node, w, h): (Node<()>, f32, f32) = CellBuffer::from(source).get_node_with_size(&settings);
+    let mut svg = String::new();
+    node.render(&mut svg)?;
+
+    // Marker definitions have fixed IDs
+    let svg = ID_RE.replace_all(&svg, |cap: &regex::Captures| {
+        format!("id=\"{}-{}\"", id, &cap[1])
+    });
+    let svg = REF_RE.replace_all(&svg, |cap: &regex::Captures| {
+        format!("url(#{}-{})", id, &cap[1])
+    });
+
+    // Scaled down by the stylesheet on narrow screens
+    Ok(svg.replacen("<svg ", &format!("<svg viewBox=\"0 0 {} {}\" ", w, h), 1))
+}
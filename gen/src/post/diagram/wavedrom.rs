@@ -0,0 +1,453 @@
+//! Timing diagrams, covering a subset of WaveDrom's WaveJSON.
+//!
+//! Supported wave characters are clocks (`p`, `n`, `h`, `l` and their capitalized forms),
+//! levels (`0`, `1`, `z`, `u`, `d`), buses (`x`, `=`, `2`-`9`), `.` for extending the previous
+//! state and `|` for gaps. Lanes support `name`, `wave`, `data`, `period` and `phase`, and
+//! nested groups are rendered with their names as separate lanes. Other keys, e.g. `node`
+//! and `edge`, are rejected rather than ignored.
+
+use std::fmt::Write;
+
+use pulldown_cmark_escape::escape_html;
+use serde::Deserialize;
+use unicode_width::UnicodeWidthStr;
+
+const CYCLE: f64 = 20.;
+const LANE: f64 = 30.;
+const HEIGHT: f64 = 20.;
+const SLOPE: f64 = 3.;
+const FONT_SIZE: f64 = 12.;
+/// Rough width of a narrow character at `FONT_SIZE`
+const CHAR_W: f64 = 7.;
+const INDENT: f64 = 8.;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Diagram {
+    signal: Vec<serde_json::Value>,
+    head: Option<Caption>,
+    foot: Option<Caption>,
+    #[serde(default)]
+    config: Config,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Caption {
+    text: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    hscale: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Lane {
+    name: Option<String>,
+    wave: Option<String>,
+    data: Option<Data>,
+    period: Option<f64>,
+    phase: Option<f64>,
+    /// Nesting depth, set for group members
+    #[serde(skip)]
+    depth: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Words(String),
+    List(Vec<String>),
+}
+
+fn flatten(
+    items: Vec<serde_json::Value>,
+    depth: usize,
+    lanes: &mut Vec<Lane>,
+) -> anyhow::Result<()> {
+    for item in items {
+        match item {
+            serde_json::Value::Array(mut group) => {
+                let name = match group.first() {
+                    Some(serde_json::Value::String(_)) => {
+                        let serde_json::Value::String(name) = group.remove(0) else {
+                            unreachable!()
+                        };
+                        Some(name)
+                    }
+                    _ => None,
+                };
+                lanes.push(Lane {
+                    name,
+                    depth,
+                    ..Default::default()
+                });
+                flatten(group, depth + 1, lanes)?;
+            }
+            item => {
+                let mut lane: Lane = serde_json::from_value(item)?;
+                lane.depth = depth;
+                lanes.push(lane);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Low,
+    Mid,
+    High,
+}
+
+enum Shape {
+    Level(Level),
+    /// Class of the fill, and the label
+    Bus(&'static str, Option<String>),
+}
+
+struct Seg {
+    x0: f64,
+    x1: f64,
+    shape: Shape,
+    /// Vertical transition into this segment, used by clocks
+    sharp: bool,
+}
+
+/// Splits a wave into segments, returning them along with the positions of gaps.
+fn parse_wave(wave: &str, width: f64, data: Option<Data>) -> anyhow::Result<(Vec<Seg>, Vec<f64>)> {
+    let mut labels = match data {
+        None => Vec::new(),
+        Some(Data::Words(s)) => s.split_whitespace().map(str::to_owned).collect(),
+        Some(Data::List(l)) => l,
+    }
+    .into_iter();
+    let mut segs: Vec<Seg> = Vec::new();
+    let mut gaps = Vec::new();
+    let mut clock = None;
+
+    for (i, c) in wave.chars().enumerate() {
+        let x0 = i as f64 * width;
+        let x1 = x0 + width;
+        let c = match c {
+            '.' | '|' => {
+                if c == '|' {
+                    gaps.push(x0 + width / 2.);
+                }
+                match (clock, segs.last_mut()) {
+                    (Some(clock), _) => clock,
+                    (None, Some(last)) => {
+                        last.x1 = x1;
+                        continue;
+                    }
+                    (None, None) => 'x',
+                }
+            }
+            c => c,
+        };
+        clock = None;
+
+        let mut push = |x0, x1, shape, sharp| {
+            segs.push(Seg {
+                x0,
+                x1,
+                shape,
+                sharp,
+            })
+        };
+        match c {
+            'p' | 'P' | 'n' | 'N' => {
+                clock = Some(c);
+                let (first, second) = if c.eq_ignore_ascii_case(&'p') {
+                    (Level::High, Level::Low)
+                } else {
+                    (Level::Low, Level::High)
+                };
+                let mid = x0 + width / 2.;
+                push(x0, mid, Shape::Level(first), true);
+                push(mid, x1, Shape::Level(second), true);
+            }
+            'h' | 'H' => push(x0, x1, Shape::Level(Level::High), true),
+            'l' | 'L' => push(x0, x1, Shape::Level(Level::Low), true),
+            '1' | 'u' => push(x0, x1, Shape::Level(Level::High), false),
+            '0' | 'd' => push(x0, x1, Shape::Level(Level::Low), false),
+            'z' => push(x0, x1, Shape::Level(Level::Mid), false),
+            'x' => push(x0, x1, Shape::Bus("wave-x", None), false),
+            '=' | '2'..='9' => {
+                const CLASSES: [&str; 8] = [
+                    "wave-data-2",
+                    "wave-data-3",
+                    "wave-data-4",
+                    "wave-data-5",
+                    "wave-data-6",
+                    "wave-data-7",
+                    "wave-data-8",
+                    "wave-data-9",
+                ];
+                let class = CLASSES[c.to_digit(10).unwrap_or(2) as usize - 2];
+                push(x0, x1, Shape::Bus(class, labels.next()), false)
+            }
+            c => return Err(anyhow::anyhow!("Unsupported wave character {:?}", c)),
+        }
+    }
+
+    Ok((segs, gaps))
+}
+
+fn points(pts: &[(f64, f64)]) -> String {
+    let mut d = String::new();
+    for (i, (x, y)) in pts.iter().enumerate() {
+        write!(d, "{}{} {}", if i == 0 { "M" } else { "L" }, x, y).unwrap();
+    }
+    d
+}
+
+/// Draws the segments of a lane with its top edge at `top` and cycle 0 at `ox`.
+fn draw_lane(out: &mut String, segs: &[Seg], ox: f64, top: f64, id: &str) {
+    let (hi, lo, mid) = (top, top + HEIGHT, top + HEIGHT / 2.);
+
+    let mut strokes = String::new();
+    for (k, seg) in segs.iter().enumerate() {
+        let prev = k.checked_sub(1).map(|j| &segs[j].shape);
+        let next = segs.get(k + 1).map(|s| &s.shape);
+        let (x0, x1) = (ox + seg.x0, ox + seg.x1);
+        let s = if seg.sharp { 0. } else { SLOPE };
+
+        match &seg.shape {
+            Shape::Level(l) => {
+                let y = y_of(*l, top);
+                match prev {
+                    None => strokes.push_str(&points(&[(x0, y), (x1, y)])),
+                    Some(Shape::Level(p)) => {
+                        strokes.push_str(&points(&[(x0, y_of(*p, top)), (x0 + s, y), (x1, y)]))
+                    }
+                    Some(Shape::Bus(..)) => {
+                        strokes.push_str(&points(&[(x0, hi), (x0 + s, y), (x1, y)]));
+                        strokes.push_str(&points(&[(x0, lo), (x0 + s, y)]));
+                    }
+                }
+            }
+            Shape::Bus(class, label) => {
+                let left = match prev {
+                    None => None,
+                    Some(Shape::Level(p)) => Some((x0, y_of(*p, top))),
+                    Some(Shape::Bus(..)) => Some((x0 + s / 2., mid)),
+                };
+                let right = match next {
+                    Some(Shape::Bus(..)) => Some((x1 + SLOPE / 2., mid)),
+                    _ => None,
+                };
+                let lx = if prev.is_none() { x0 } else { x0 + s };
+
+                let upper: Vec<_> = left
+                    .into_iter()
+                    .chain([(lx, hi), (x1, hi)])
+                    .chain(right)
+                    .collect();
+                let lower: Vec<_> = left
+                    .into_iter()
+                    .chain([(lx, lo), (x1, lo)])
+                    .chain(right)
+                    .collect();
+                let skip = left.iter().count();
+                let outline: Vec<_> = upper
+                    .iter()
+                    .chain(lower[skip..].iter().rev())
+                    .copied()
+                    .collect();
+                // Undefined values are hatched, data is colored by the stylesheet
+                if *class == "wave-x" {
+                    write!(out, "<path fill=\"url(#{}-hatch)\"", id).unwrap();
+                } else {
+                    write!(out, "<path class=\"{}\"", class).unwrap();
+                }
+                write!(out, " d=\"{}Z\"/>", points(&outline)).unwrap();
+                strokes.push_str(&points(&upper));
+                strokes.push_str(&points(&lower));
+
+                if let Some(label) = label {
+                    write!(
+                        out,
+                        "<text x=\"{}\" y=\"{}\" font-size=\"{}\" text-anchor=\"middle\" dominant-baseline=\"central\" fill=\"currentColor\">",
+                        (lx + x1) / 2.,
+                        mid,
+                        FONT_SIZE - 1.
+                    )
+                    .unwrap();
+                    escape_html(&mut *out, label).unwrap();
+                    out.push_str("</text>");
+                }
+            }
+        }
+    }
+    write!(
+        out,
+        "<path d=\"{}\" fill=\"none\" stroke=\"currentColor\" stroke-width=\"1.5\" stroke-linejoin=\"round\"/>",
+        strokes
+    )
+    .unwrap();
+}
+
+fn y_of(level: Level, top: f64) -> f64 {
+    match level {
+        Level::High => top,
+        Level::Low => top + HEIGHT,
+        Level::Mid => top + HEIGHT / 2.,
+    }
+}
+
+fn caption(out: &mut String, text: &str, x: f64, y: f64) {
+    write!(
+        out,
+        "<text x=\"{}\" y=\"{}\" font-size=\"{}\" font-weight=\"bold\" text-anchor=\"middle\" dominant-baseline=\"central\" fill=\"currentColor\">",
+        x,
+        y,
+        FONT_SIZE + 2.
+    )
+    .unwrap();
+    escape_html(&mut *out, text).unwrap();
+    out.push_str("</text>");
+}
+
+pub fn render(source: &str, id: &str) -> anyhow::Result<String> {
+    let diagram: Diagram = json5::from_str(source)?;
+    let cycle = CYCLE * diagram.config.hscale.unwrap_or(1.);
+    let mut lanes = Vec::new();
+    flatten(diagram.signal, 0, &mut lanes)?;
+
+    let name_w = lanes
+        .iter()
+        .filter_map(|l| {
+            let name = l.name.as_ref()?;
+            Some(l.depth as f64 * INDENT + name.width() as f64 * CHAR_W)
+        })
+        .fold(0., f64::max)
+        + 2. * INDENT;
+    let head_h = if diagram.head.is_some() { LANE } else { 0. };
+    let foot_h = if diagram.foot.is_some() { LANE } else { 0. };
+
+    let mut waves = String::new();
+    let mut names = String::new();
+    let mut gaps = String::new();
+    let mut cycles: f64 = 0.;
+    let lanes_h = lanes.len() as f64 * LANE;
+    for (i, lane) in lanes.into_iter().enumerate() {
+        let top = head_h + i as f64 * LANE + (LANE - HEIGHT) / 2.;
+        if let Some(name) = &lane.name {
+            let class = if lane.wave.is_none() {
+                " font-weight=\"bold\""
+            } else {
+                ""
+            };
+            write!(
+                names,
+                "<text x=\"{}\" y=\"{}\"{}>",
+                INDENT + lane.depth as f64 * INDENT,
+                top + HEIGHT / 2.,
+                class
+            )
+            .unwrap();
+            escape_html(&mut names, name).unwrap();
+            names.push_str("</text>");
+        }
+
+        let Some(wave) = &lane.wave else { continue };
+        let period = lane.period.unwrap_or(1.);
+        let phase = lane.phase.unwrap_or(0.);
+        let (segs, lane_gaps) = parse_wave(wave, cycle * period, lane.data)?;
+        cycles = cycles.max(wave.chars().count() as f64 * period - phase);
+
+        let ox = name_w - phase * cycle;
+        draw_lane(&mut waves, &segs, ox, top, id);
+        for x in lane_gaps {
+            let x = ox + x;
+            let (y0, y1) = (top + HEIGHT + 3., top - 3.);
+            write!(
+                gaps,
+                "<path class=\"wave-gap\" d=\"M{} {}L{} {}L{} {}L{} {}Z\"/><path d=\"M{} {}L{} {}M{} {}L{} {}\"/>",
+                x - 4.,
+                y0,
+                x,
+                y1,
+                x + 3.,
+                y1,
+                x - 1.,
+                y0,
+                x - 4.,
+                y0,
+                x,
+                y1,
+                x - 1.,
+                y0,
+                x + 3.,
+                y1,
+            )
+            .unwrap();
+        }
+    }
+
+    let width = name_w + cycles.max(0.) * cycle + INDENT;
+    let height = head_h + lanes_h + foot_h;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+        w = width,
+        h = height
+    );
+    write!(
+        svg,
+        "<defs>\
+        <pattern id=\"{id}-hatch\" width=\"4\" height=\"4\" patternUnits=\"userSpaceOnUse\">\
+        <path d=\"M-1 1L1 -1M0 4L4 0M3 5L5 3\" stroke=\"currentColor\" stroke-width=\"0.5\"/></pattern>\
+        <clipPath id=\"{id}-clip\"><rect x=\"{x}\" y=\"0\" width=\"{cw}\" height=\"{h}\"/></clipPath>\
+        </defs>",
+        id = id,
+        x = name_w,
+        cw = cycles.max(0.) * cycle + SLOPE,
+        h = height,
+    )
+    .unwrap();
+
+    // Cycle boundaries
+    svg.push_str("<g class=\"wave-grid\" stroke=\"currentColor\" stroke-dasharray=\"1,3\">");
+    for i in 0..=cycles.ceil() as usize {
+        let x = name_w + i as f64 * cycle;
+        write!(
+            svg,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\"/>",
+            x,
+            head_h,
+            x,
+            head_h + lanes_h
+        )
+        .unwrap();
+    }
+    svg.push_str("</g>");
+
+    write!(
+        svg,
+        "<g fill=\"currentColor\" font-size=\"{}\" dominant-baseline=\"central\">{}</g>",
+        FONT_SIZE, names
+    )
+    .unwrap();
+    write!(
+        svg,
+        "<g clip-path=\"url(#{}-clip)\" fill=\"none\">{}",
+        id, waves
+    )
+    .unwrap();
+    write!(svg, "<g stroke=\"currentColor\">{}</g></g>", gaps).unwrap();
+
+    if let Some(head) = &diagram.head {
+        caption(&mut svg, &head.text, width / 2., head_h / 2.);
+    }
+    if let Some(foot) = &diagram.foot {
+        caption(&mut svg, &foot.text, width / 2., height - foot_h / 2.);
+    }
+    svg.push_str("</svg>");
+    Ok(svg)
+}
@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag, TagEnd};
+use regex::Regex;
+
+use super::xref::{self, Kind, Xrefs};
+use super::{chart, diagram};
+
+/// Closes a caption. The plaintext generator breaks lines here.
+pub const CAPTION_END: &str = "</figcaption>";
+
+static ATTRS_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([^{}]*)\}\s*$").unwrap());
+static ATTR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"#([\w:-]+)|(\w+)=(?:"([^"]*)"|([^\s"]+))"#).unwrap());
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+#[derive(Default)]
+struct Attrs {
+    id: Option<String>,
+    caption: Option<String>,
+}
+
+fn parse_attrs(s: &str) -> anyhow::Result<Attrs> {
+    let mut attrs = Attrs::default();
+    let mut last = 0;
+    for cap in ATTR_RE.captures_iter(s) {
+        let whole = cap.get(0).unwrap();
+        if !s[last..whole.start()].trim().is_empty() {
+            break;
+        }
+        last = whole.end();
+
+        if let Some(id) = cap.get(1) {
+            attrs.id = Some(id.as_str().to_owned());
+            continue;
+        }
+        let value = cap.get(3).or(cap.get(4)).unwrap().as_str().to_owned();
+        match cap.get(2).unwrap().as_str() {
+            "caption" => attrs.caption = Some(value),
+            key => return Err(anyhow::anyhow!("Unsupported attribute: {}", key)),
+        }
+    }
+    if !s[last..].trim().is_empty() {
+        return Err(anyhow::anyhow!("Malformed attributes: {{{}}}", s));
+    }
+    Ok(attrs)
+}
+
+/// Splits trailing `{#label caption="..."}` attributes off `s`.
+fn split_attrs(s: &str) -> anyhow::Result<(&str, Option<Attrs>)> {
+    match ATTRS_RE.captures(s) {
+        Some(cap) => Ok((
+            &s[..cap.get(0).unwrap().start()],
+            Some(parse_attrs(cap.get(1).unwrap().as_str())?),
+        )),
+        None => Ok((s, None)),
+    }
+}
+
+/// Takes events from `input` until the end of the block started by `start`.
+fn take_block<'a>(
+    input: &mut VecDeque<Spanned<'a>>,
+    start: Spanned<'a>,
+    end: TagEnd,
+) -> Vec<Spanned<'a>> {
+    let mut block = vec![start];
+    while let Some(ev) = input.pop_front() {
+        let is_end = ev.0 == Event::End(end);
+        block.push(ev);
+        if is_end {
+            break;
+        }
+    }
+    block
+}
+
+fn open(output: &mut Vec<Spanned>, kind: Kind, label: Option<&str>, range: &Range<usize>) {
+    let class = match kind {
+        Kind::Figure => "image",
+        Kind::Table => "table",
+        Kind::Listing => "listing",
+        Kind::Theorem | Kind::Lemma | Kind::Definition => unreachable!(),
+    };
+    let html = match label {
+        Some(label) => format!(
+            "<figure class=\"{}\" id=\"{}\">\n",
+            class,
+            xref::anchor(label)
+        ),
+        None => format!("<figure class=\"{}\">\n", class),
+    };
+    output.push((Event::Html(html.into()), range.clone()));
+}
+
+fn caption<'a>(
+    output: &mut Vec<Spanned<'a>>,
+    text: Vec<Spanned<'a>>,
+    kind: Kind,
+    num: usize,
+    lang: &str,
+    range: &Range<usize>,
+) {
+    let number = format!("{} {}", kind.name(lang), num);
+    output.extend(
+        [
+            Event::Html("<figcaption>".into()),
+            Event::InlineHtml("<span class=\"caption-number\">".into()),
+            Event::Text(number.into()),
+            Event::InlineHtml("</span>".into()),
+        ]
+        .map(|ev| (ev, range.clone())),
+    );
+    if !text.is_empty() {
+        output.push((
+            Event::Text(xref::caption_separator(lang).into()),
+            range.clone(),
+        ));
+        output.extend(text);
+    }
+    output.push((Event::Html(CAPTION_END.into()), range.clone()));
+}
+
+/// Recognizes a paragraph consisting of a single image, optionally followed by attributes.
+/// Returns the attributes if the image should become a figure.
+fn image_figure(para: &[Spanned]) -> anyhow::Result<Option<Attrs>> {
+    let Some((Event::Start(Tag::Image { title, .. }), _)) = para.get(1) else {
+        return Ok(None);
+    };
+    let Some(image_end) = para
+        .iter()
+        .position(|(ev, _)| *ev == Event::End(TagEnd::Image))
+    else {
+        return Ok(None);
+    };
+
+    let mut trailing = String::new();
+    for (ev, _) in &para[image_end + 1..para.len() - 1] {
+        match ev {
+            Event::Text(s) => trailing += s.as_ref(),
+            _ => return Ok(None),
+        }
+    }
+    let (before, attrs) = split_attrs(&trailing)?;
+    if !before.trim().is_empty() {
+        return Ok(None);
+    }
+    match attrs {
+        Some(attrs) => Ok(Some(attrs)),
+        None if !title.is_empty() => Ok(Some(Attrs::default())),
+        None => Ok(None),
+    }
+}
+
+fn is_table_caption(para: &[Spanned]) -> bool {
+    matches!(para.get(1), Some((Event::Text(s), _)) if s.starts_with("Table:"))
+}
+
+/// Strips the `Table:` prefix and trailing attributes from a caption paragraph,
+/// returning the inline content and the attributes.
+fn table_caption(mut para: Vec<Spanned>) -> anyhow::Result<(Vec<Spanned>, Attrs)> {
+    // Drop paragraph start and end
+    para.pop();
+    para.remove(0);
+
+    if let (Event::Text(s), _) = &mut para[0] {
+        let stripped = s["Table:".len()..].trim_start().to_owned();
+        *s = stripped.into();
+    }
+    let mut attrs = Attrs::default();
+    if let Some((Event::Text(s), _)) = para.last_mut() {
+        let (before, parsed) = split_attrs(s)?;
+        if let Some(parsed) = parsed {
+            let before = before.trim_end().to_owned();
+            *s = before.into();
+            attrs = parsed;
+        }
+    }
+    para.retain(|(ev, _)| !matches!(ev, Event::Text(s) if s.is_empty()));
+    Ok((para, attrs))
+}
+
+fn table<'a>(
+    output: &mut Vec<Spanned<'a>>,
+    table: Vec<Spanned<'a>>,
+    caption_para: Vec<Spanned<'a>>,
+    xrefs: &mut Xrefs,
+    lang: &str,
+) -> anyhow::Result<()> {
+    let range = table[0].1.clone();
+    let (text, attrs) = table_caption(caption_para)?;
+    let num = xrefs.number(Kind::Table, attrs.id.as_deref())?;
+
+    open(output, Kind::Table, attrs.id.as_deref(), &range);
+    caption(output, text, Kind::Table, num, lang, &range);
+    output.extend(table);
+    output.push((Event::Html("</figure>\n".into()), range));
+    Ok(())
+}
+
+/// Wraps captioned or labelled images, tables and code listings into numbered figures.
+///
+/// - A paragraph containing only an image with a title, or followed by `{#fig:label}`,
+///   becomes a figure with the title (or alt text) as caption.
+/// - A table preceded or followed by a `Table: caption {#tbl:label}` paragraph becomes a
+///   figure with the paragraph as caption.
+/// - A fenced code block with `{#lst:label caption="..."}` after the language becomes
+///   a figure as well. Diagram and chart blocks take `{#fig:label}` instead.
+///
+/// All targets are numbered into `xrefs` in document order.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    xrefs: &mut Xrefs,
+    lang: &str,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+
+    while let Some((event, range)) = input.pop_front() {
+        match event {
+            Event::Start(Tag::Paragraph) => {
+                let para = take_block(&mut input, (event, range.clone()), TagEnd::Paragraph);
+
+                if is_table_caption(&para)
+                    && let Some((Event::Start(Tag::Table(_)), _)) = input.front()
+                {
+                    let start = input.pop_front().unwrap();
+                    let tbl = take_block(&mut input, start, TagEnd::Table);
+                    if let Err(e) = table(&mut output, tbl, para, xrefs, lang) {
+                        report(range.start, e);
+                    }
+                    continue;
+                }
+
+                let attrs = match image_figure(&para) {
+                    Ok(Some(attrs)) => attrs,
+                    Ok(None) => {
+                        output.extend(para);
+                        continue;
+                    }
+                    Err(e) => {
+                        report(range.start, e);
+                        continue;
+                    }
+                };
+                let num = match xrefs.number(Kind::Figure, attrs.id.as_deref()) {
+                    Ok(num) => num,
+                    Err(e) => {
+                        report(range.start, e);
+                        continue;
+                    }
+                };
+
+                let image_end = para
+                    .iter()
+                    .position(|(ev, _)| *ev == Event::End(TagEnd::Image))
+                    .unwrap();
+                let mut image: Vec<_> = para.into_iter().take(image_end + 1).skip(1).collect();
+                let text = match (&attrs.caption, &image[0].0) {
+                    (Some(caption), _) => caption.clone(),
+                    (None, Event::Start(Tag::Image { title, .. })) if !title.is_empty() => {
+                        title.to_string()
+                    }
+                    _ => image
+                        .iter()
+                        .filter_map(|(ev, _)| match ev {
+                            Event::Text(s) | Event::Code(s) => Some(s.as_ref()),
+                            _ => None,
+                        })
+                        .collect(),
+                };
+                // The caption supersedes the title
+                if let Event::Start(Tag::Image { title, .. }) = &mut image[0].0 {
+                    *title = CowStr::Borrowed("");
+                }
+
+                open(&mut output, Kind::Figure, attrs.id.as_deref(), &range);
+                output.extend(image);
+                let text = if text.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![(Event::Text(text.into()), range.clone())]
+                };
+                caption(&mut output, text, Kind::Figure, num, lang, &range);
+                output.push((Event::Html("</figure>\n".into()), range));
+            }
+            Event::Start(Tag::Table(_)) => {
+                let tbl = take_block(&mut input, (event, range.clone()), TagEnd::Table);
+                if let Some((Event::Start(Tag::Paragraph), _)) = input.front() {
+                    let start = input.pop_front().unwrap();
+                    let para = take_block(&mut input, start, TagEnd::Paragraph);
+                    if is_table_caption(&para) {
+                        if let Err(e) = table(&mut output, tbl, para, xrefs, lang) {
+                            report(range.start, e);
+                        }
+                        continue;
+                    }
+                    // Not a caption, process as usual
+                    for ev in para.into_iter().rev() {
+                        input.push_front(ev);
+                    }
+                }
+                output.extend(tbl);
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let (syntax, attrs) = match split_attrs(&info) {
+                    Ok((syntax, attrs)) => (syntax.trim().to_owned(), attrs),
+                    Err(e) => {
+                        report(range.start, e);
+                        continue;
+                    }
+                };
+                let Some(attrs) = attrs else {
+                    output.push((
+                        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))),
+                        range,
+                    ));
+                    continue;
+                };
+                // Diagrams are rendered later on, and captioned like images
+                let kind = if diagram::is_diagram(&syntax) || chart::is_chart(&syntax) {
+                    Kind::Figure
+                } else {
+                    Kind::Listing
+                };
+                let start = Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(syntax.into())));
+                let block = take_block(&mut input, (start, range.clone()), TagEnd::CodeBlock);
+                if attrs.id.is_none() && attrs.caption.is_none() {
+                    output.extend(block);
+                    continue;
+                }
+
+                let num = match xrefs.number(kind, attrs.id.as_deref()) {
+                    Ok(num) => num,
+                    Err(e) => {
+                        report(range.start, e);
+                        continue;
+                    }
+                };
+                open(&mut output, kind, attrs.id.as_deref(), &range);
+                let text = attrs
+                    .caption
+                    .map(|c| vec![(Event::Text(c.into()), range.clone())])
+                    .unwrap_or_default();
+                if kind == Kind::Listing {
+                    caption(&mut output, text, kind, num, lang, &range);
+                    output.extend(block);
+                } else {
+                    output.extend(block);
+                    caption(&mut output, text, kind, num, lang, &range);
+                }
+                output.push((Event::Html("</figure>\n".into()), range));
+            }
+            event => output.push((event, range)),
+        }
+    }
+
+    output
+}
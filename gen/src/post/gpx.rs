@@ -0,0 +1,545 @@
+//! Route maps for `{{#gpx path/to/track.gpx}}` directives, placed in their own paragraphs.
+//!
+//! Tracks are drawn as plain outlines without map tiles, along with an elevation profile
+//! (and a pace profile if the track is timed) and summary stats.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use chrono::{DateTime, FixedOffset};
+use pulldown_cmark::{Event, Tag, TagEnd};
+use regex::Regex;
+
+use super::chart::{format_tick, nice_range, round, series_color, text};
+
+static DIRECTIVE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\{\{#gpx\s+([^}\s]+)\s*\}\}$").unwrap());
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+/// Mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+/// Minimal speed counted as moving, in m/s
+const MOVING_SPEED: f64 = 0.5;
+/// Elevation changes within this range (in meters) are treated as noise
+const CLIMB_HYSTERESIS: f64 = 3.;
+/// Distance (in meters) over which pace is averaged
+const PACE_WINDOW: f64 = 200.;
+/// Points kept in the rendered outline and profile
+const MAX_POINTS: usize = 500;
+
+const MAP_SIZE: f64 = 400.;
+const PROFILE_W: f64 = 640.;
+const PROFILE_H: f64 = 200.;
+
+struct Point {
+    lat: f64,
+    lon: f64,
+    ele: Option<f64>,
+    time: Option<DateTime<FixedOffset>>,
+    /// Distance from the start, in meters
+    dist: f64,
+}
+
+fn haversine(a: &Point, b: &Point) -> f64 {
+    let (phi1, phi2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dphi = phi2 - phi1;
+    let dlambda = (b.lon - a.lon).to_radians();
+    let h = (dphi / 2.).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// Parses all track and route points, returning segments of points.
+fn parse(content: &str) -> anyhow::Result<Vec<Vec<Point>>> {
+    let doc = roxmltree::Document::parse(content)?;
+    let mut segments = Vec::new();
+    for seg in doc
+        .descendants()
+        .filter(|n| matches!(n.tag_name().name(), "trkseg" | "rte"))
+    {
+        let mut points = Vec::new();
+        for pt in seg
+            .children()
+            .filter(|n| matches!(n.tag_name().name(), "trkpt" | "rtept"))
+        {
+            let coord = |name| -> anyhow::Result<f64> {
+                Ok(pt
+                    .attribute(name)
+                    .ok_or_else(|| anyhow::anyhow!("Point without {}", name))?
+                    .parse()?)
+            };
+            let child = |name| {
+                pt.children()
+                    .find(|n| n.tag_name().name() == name)
+                    .and_then(|n| n.text())
+            };
+            points.push(Point {
+                lat: coord("lat")?,
+                lon: coord("lon")?,
+                ele: child("ele").map(str::parse).transpose()?,
+                time: child("time")
+                    .map(DateTime::parse_from_rfc3339)
+                    .transpose()?,
+                dist: 0.,
+            });
+        }
+        if !points.is_empty() {
+            segments.push(points);
+        }
+    }
+    if segments.is_empty() {
+        return Err(anyhow::anyhow!("No track or route points"));
+    }
+
+    // Gaps between segments are not counted
+    let mut dist = 0.;
+    for seg in &mut segments {
+        seg[0].dist = dist;
+        for i in 1..seg.len() {
+            dist += haversine(&seg[i - 1], &seg[i]);
+            seg[i].dist = dist;
+        }
+    }
+    Ok(segments)
+}
+
+struct Stats {
+    distance: f64,
+    climb: Option<f64>,
+    /// In seconds
+    moving_time: Option<f64>,
+}
+
+fn stats(segments: &[Vec<Point>]) -> Stats {
+    let distance = segments
+        .last()
+        .and_then(|s| s.last())
+        .map_or(0., |p| p.dist);
+
+    let mut climb = None;
+    let mut moving_time = None;
+    for seg in segments {
+        // Only count a climb once the elevation leaves the noise band around a reference
+        let mut reference = None;
+        for p in seg {
+            let Some(ele) = p.ele else { continue };
+            match reference {
+                None => reference = Some(ele),
+                Some(r) if ele > r + CLIMB_HYSTERESIS => {
+                    *climb.get_or_insert(0.) += ele - r;
+                    reference = Some(ele);
+                }
+                Some(r) if ele < r - CLIMB_HYSTERESIS => reference = Some(ele),
+                _ => {}
+            }
+        }
+
+        for pair in seg.windows(2) {
+            let (Some(t0), Some(t1)) = (pair[0].time, pair[1].time) else {
+                continue;
+            };
+            let dt = (t1 - t0).num_milliseconds() as f64 / 1000.;
+            let total = moving_time.get_or_insert(0.);
+            if dt > 0. && (pair[1].dist - pair[0].dist) / dt >= MOVING_SPEED {
+                *total += dt;
+            }
+        }
+    }
+
+    Stats {
+        distance,
+        climb,
+        moving_time,
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn labels(lang: &str) -> [&'static str; 6] {
+    match lang {
+        "zh-CN" => ["距离", "爬升", "移动时间", "平均配速", "海拔", "配速"],
+        _ => [
+            "Distance",
+            "Climb",
+            "Moving time",
+            "Avg. pace",
+            "Elevation",
+            "Pace",
+        ],
+    }
+}
+
+/// Evenly picks at most `MAX_POINTS` points, always keeping the last one
+fn sample(points: &[&Point]) -> Vec<usize> {
+    let step = points.len().div_ceil(MAX_POINTS).max(1);
+    let mut indices: Vec<_> = (0..points.len()).step_by(step).collect();
+    if indices.last() != Some(&(points.len() - 1)) {
+        indices.push(points.len() - 1);
+    }
+    indices
+}
+
+fn render_map(segments: &[Vec<Point>], palette: &[String]) -> String {
+    let all = segments.iter().flatten();
+    let (mut lat0, mut lat1, mut lon0, mut lon1) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+    for p in all {
+        (lat0, lat1) = (lat0.min(p.lat), lat1.max(p.lat));
+        (lon0, lon1) = (lon0.min(p.lon), lon1.max(p.lon));
+    }
+
+    // Equirectangular projection around the center, in meters
+    let cos = ((lat0 + lat1) / 2.).to_radians().cos();
+    let project = |p: &Point| {
+        (
+            (p.lon - lon0).to_radians() * cos * EARTH_RADIUS,
+            (lat1 - p.lat).to_radians() * EARTH_RADIUS,
+        )
+    };
+    let w = ((lon1 - lon0).to_radians() * cos * EARTH_RADIUS).max(1.);
+    let h = ((lat1 - lat0).to_radians() * EARTH_RADIUS).max(1.);
+    let pad = 20.;
+    let scale = (MAP_SIZE - 2. * pad) / w.max(h);
+    let (width, height) = (w * scale + 2. * pad, h * scale + 2. * pad + 20.);
+    let xy = |p: &Point| {
+        let (x, y) = project(p);
+        (round(x * scale + pad), round(y * scale + pad))
+    };
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" font-size=\"12\" fill=\"currentColor\">",
+        w = round(width),
+        h = round(height)
+    );
+    let mut d = String::new();
+    for seg in segments {
+        let refs: Vec<_> = seg.iter().collect();
+        for (i, idx) in sample(&refs).into_iter().enumerate() {
+            let (x, y) = xy(refs[idx]);
+            write!(d, "{}{} {}", if i == 0 { "M" } else { "L" }, x, y).unwrap();
+        }
+    }
+    write!(
+        svg,
+        "<path d=\"{}\" fill=\"none\" style=\"stroke: {}\" stroke-width=\"3\" stroke-linejoin=\"round\" stroke-linecap=\"round\"/>",
+        d,
+        series_color(palette, 0)
+    )
+    .unwrap();
+
+    // Start and finish
+    let start = xy(&segments[0][0]);
+    let end = xy(segments.last().unwrap().last().unwrap());
+    write!(
+        svg,
+        "<circle cx=\"{}\" cy=\"{}\" r=\"5\" style=\"fill: var(--color-bg)\" stroke=\"currentColor\" stroke-width=\"2\"/>\
+        <rect x=\"{}\" y=\"{}\" width=\"10\" height=\"10\" stroke=\"currentColor\" stroke-width=\"2\"/>",
+        start.0,
+        start.1,
+        end.0 - 5.,
+        end.1 - 5.
+    )
+    .unwrap();
+
+    // Scale bar, about a quarter of the map width
+    let (_, _, bar) = nice_range(0., w / 4. * 5.);
+    let bar_w = round(bar * scale);
+    let y = height - 12.;
+    write!(
+        svg,
+        "<path d=\"M{x0} {y0}L{x0} {y}L{x1} {y}L{x1} {y0}\" fill=\"none\" stroke=\"currentColor\"/>",
+        x0 = pad,
+        x1 = pad + bar_w,
+        y = y,
+        y0 = y - 4.
+    )
+    .unwrap();
+    let label = if bar >= 1000. {
+        format!("{} km", bar / 1000.)
+    } else {
+        format!("{} m", bar)
+    };
+    text(
+        &mut svg,
+        pad + bar_w + 6.,
+        y,
+        "dominant-baseline=\"central\"",
+        &label,
+    );
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Pace in min/km, averaged over the last `PACE_WINDOW` meters
+fn pace(points: &[&Point]) -> Vec<Option<f64>> {
+    let mut j = 0;
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            while j + 1 < i && p.dist - points[j + 1].dist >= PACE_WINDOW {
+                j += 1;
+            }
+            let q = points[j];
+            let dist = p.dist - q.dist;
+            let dt = (p.time? - q.time?).num_milliseconds() as f64 / 1000.;
+            (dist >= PACE_WINDOW / 2. && dt > 0.).then(|| dt / dist * 1000. / 60.)
+        })
+        .collect()
+}
+
+fn render_profile(segments: &[Vec<Point>], palette: &[String], lang: &str) -> Option<String> {
+    let points: Vec<_> = segments.iter().flatten().collect();
+    let elevations: Vec<_> = points.iter().filter_map(|p| p.ele).collect();
+    if elevations.is_empty() {
+        return None;
+    }
+    let paces = pace(&points);
+    let [.., ele_label, pace_label] = labels(lang);
+
+    let (min, max) = elevations
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(lo, hi), &e| (lo.min(e), hi.max(e)));
+    let (e_lo, e_hi, e_step) = nice_range(min, max);
+    let total = points.last().unwrap().dist / 1000.;
+    let (_, d_hi, d_step) = nice_range(0., total);
+
+    // Pace is clipped, since pauses produce arbitrarily slow paces
+    let mut sorted: Vec<_> = paces.iter().flatten().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let pace_range = (!sorted.is_empty()).then(|| {
+        let median = sorted[sorted.len() / 2];
+        nice_range(sorted[0], sorted[sorted.len() - 1].min(median * 2.))
+    });
+
+    let (left, right, top, bottom) = (50., PROFILE_W - 50., 25., PROFILE_H - 25.);
+    let sx = |km: f64| round(left + km / d_hi * (right - left));
+    let sy = |lo: f64, hi: f64, v: f64| {
+        round(bottom - (v.clamp(lo, hi) - lo) / (hi - lo) * (bottom - top))
+    };
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" font-size=\"12\" fill=\"currentColor\">",
+        w = PROFILE_W,
+        h = PROFILE_H
+    );
+
+    // Elevation as a filled area
+    let indices = sample(&points);
+    let mut d = String::new();
+    let mut first = None;
+    let mut last = 0.;
+    for &i in &indices {
+        let Some(ele) = points[i].ele else { continue };
+        let x = sx(points[i].dist / 1000.);
+        write!(
+            d,
+            "{}{} {}",
+            if first.is_none() { "M" } else { "L" },
+            x,
+            sy(e_lo, e_hi, ele)
+        )
+        .unwrap();
+        first.get_or_insert(x);
+        last = x;
+    }
+    write!(
+        svg,
+        "<path d=\"{}L{} {}L{} {}Z\" fill=\"currentColor\" fill-opacity=\"0.15\" stroke=\"currentColor\" stroke-opacity=\"0.5\"/>",
+        d,
+        last,
+        bottom,
+        first.unwrap_or(left),
+        bottom
+    )
+    .unwrap();
+
+    if let Some((p_lo, p_hi, _)) = pace_range {
+        let mut d = String::new();
+        let mut pen = false;
+        for &i in &indices {
+            match paces[i] {
+                Some(p) => {
+                    let (x, y) = (sx(points[i].dist / 1000.), sy(p_lo, p_hi, p));
+                    write!(d, "{}{} {}", if pen { "L" } else { "M" }, x, y).unwrap();
+                    pen = true;
+                }
+                None => pen = false,
+            }
+        }
+        write!(
+            svg,
+            "<path d=\"{}\" fill=\"none\" style=\"stroke: {}\" stroke-width=\"1.5\" stroke-linejoin=\"round\"/>",
+            d,
+            series_color(palette, 1)
+        )
+        .unwrap();
+    }
+
+    // Axes
+    write!(
+        svg,
+        "<path d=\"M{l} {t}L{l} {b}L{r} {b}\" fill=\"none\" stroke=\"currentColor\"/>",
+        l = left,
+        t = top,
+        b = bottom,
+        r = right
+    )
+    .unwrap();
+    let mut km = 0.;
+    while km <= d_hi + d_step / 2. {
+        text(
+            &mut svg,
+            sx(km),
+            bottom + 16.,
+            "text-anchor=\"middle\"",
+            &format!("{} km", format_tick(km, d_step)),
+        );
+        km += d_step;
+    }
+    let mut ele = e_lo;
+    while ele <= e_hi + e_step / 2. {
+        text(
+            &mut svg,
+            left - 6.,
+            sy(e_lo, e_hi, ele),
+            "text-anchor=\"end\" dominant-baseline=\"central\"",
+            &format_tick(ele, e_step),
+        );
+        ele += e_step;
+    }
+    text(
+        &mut svg,
+        left - 6.,
+        top - 12.,
+        "text-anchor=\"end\" font-size=\"10\"",
+        &format!("{} (m)", ele_label),
+    );
+    if let Some((p_lo, p_hi, p_step)) = pace_range {
+        let mut p = p_lo;
+        while p <= p_hi + p_step / 2. {
+            let secs = (p * 60.).round() as u64;
+            text(
+                &mut svg,
+                right + 6.,
+                sy(p_lo, p_hi, p),
+                &format!(
+                    "dominant-baseline=\"central\" style=\"fill: {}\"",
+                    series_color(palette, 1)
+                ),
+                &format!("{}:{:02}", secs / 60, secs % 60),
+            );
+            p += p_step;
+        }
+        text(
+            &mut svg,
+            right + 6.,
+            top - 12.,
+            "font-size=\"10\"",
+            &format!("{} (/km)", pace_label),
+        );
+    }
+
+    svg.push_str("</svg>");
+    Some(svg)
+}
+
+fn render(content: &str, palette: &[String], lang: &str) -> anyhow::Result<String> {
+    let segments = parse(content)?;
+    let stats = stats(&segments);
+    let [distance, climb, moving_time, avg_pace, ..] = labels(lang);
+
+    let mut html = String::from("<div class=\"gpx\">\n");
+    writeln!(
+        html,
+        "<div class=\"diagram diagram-gpx-map\">{}</div>",
+        render_map(&segments, palette)
+    )
+    .unwrap();
+    if let Some(profile) = render_profile(&segments, palette, lang) {
+        writeln!(
+            html,
+            "<div class=\"diagram diagram-gpx-profile\">{}</div>",
+            profile
+        )
+        .unwrap();
+    }
+
+    html.push_str("<dl class=\"gpx-stats\">");
+    let mut stat = |name: &str, value: String| {
+        write!(html, "<div><dt>{}</dt><dd>{}</dd></div>", name, value).unwrap();
+    };
+    stat(distance, format!("{:.2} km", stats.distance / 1000.));
+    if let Some(climb_m) = stats.climb {
+        stat(climb, format!("{:.0} m", climb_m));
+    }
+    if let Some(time) = stats.moving_time {
+        stat(moving_time, format_duration(time));
+        if stats.distance > 0. {
+            let secs = (time / stats.distance * 1000.).round() as u64;
+            stat(avg_pace, format!("{}:{:02} /km", secs / 60, secs % 60));
+        }
+    }
+    html.push_str("</dl>\n</div>\n");
+    Ok(html)
+}
+
+/// Replaces paragraphs consisting of a `{{#gpx path}}` directive with route maps. Files are
+/// read with `load`.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    palette: &[String],
+    lang: &str,
+    load: impl Fn(&str) -> anyhow::Result<String>,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+
+    while let Some((event, range)) = input.pop_front() {
+        if !matches!(event, Event::Start(Tag::Paragraph)) {
+            output.push((event, range));
+            continue;
+        }
+
+        // Directives may be split into multiple text events
+        let mut directive = String::new();
+        let mut len = 0;
+        for (ev, _) in input.iter() {
+            match ev {
+                Event::Text(s) => directive += s.as_ref(),
+                Event::End(TagEnd::Paragraph) => break,
+                _ => {
+                    directive.clear();
+                    break;
+                }
+            }
+            len += 1;
+        }
+        let Some(cap) = DIRECTIVE_RE.captures(directive.trim()) else {
+            output.push((event, range));
+            continue;
+        };
+        let path = cap.get(1).unwrap().as_str();
+        match load(path).and_then(|content| render(&content, palette, lang)) {
+            Ok(html) => {
+                input.drain(..=len);
+                output.push((Event::Html(html.into()), range));
+            }
+            Err(e) => {
+                report(
+                    range.start,
+                    anyhow::anyhow!("Failed to render {}: {}", path, e),
+                );
+                output.push((event, range));
+            }
+        }
+    }
+
+    output
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+use base64::Engine;
+use image::{DynamicImage, ImageDecoder, ImageReader, imageops::FilterType};
+use img_parts::{DynImage, ImageEXIF, jpeg::markers};
+use pulldown_cmark_escape::escape_html;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+/// Widths of responsive variants. Variants wider than the original are skipped.
+const VARIANT_WIDTHS: &[u32] = &[480, 960, 1440, 1920];
+const PLACEHOLDER_WIDTH: u32 = 16;
+const WEBP_QUALITY: f32 = 80.0;
+const AVIF_QUALITY: u8 = 70;
+const AVIF_SPEED: u8 = 6;
+
+/// Output location of processed images
+#[derive(Clone)]
+pub struct Assets {
+    /// Directory to write images into
+    pub dir: PathBuf,
+    /// URL prefix the directory is served under
+    pub url: String,
+    /// Publish images with their original EXIF metadata, which may include GPS location
+    pub keep_exif: bool,
+    /// Fail on missing assets instead of writing them. Assets are named by content hash, so
+    /// existing ones are up to date.
+    pub verify: bool,
+}
+
+/// Photo metadata extracted from EXIF
+#[derive(Clone, Default)]
+pub struct PhotoMeta {
+    pub taken: Option<String>,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Image {
+    pub src: String,
+    pub size: Option<(u32, u32)>,
+    /// (MIME type, srcset) of responsive variants in modern formats
+    pub sources: Vec<(&'static str, String)>,
+    /// Data URI of a tiny version, shown while loading
+    pub placeholder: Option<String>,
+    pub photo: Option<PhotoMeta>,
+}
+
+static IMG_TAG_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<img\b[^>]*>").unwrap());
+static SRC_ATTR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\bsrc\s*=\s*"([^"]*)""#).unwrap());
+static WIDTH_ATTR_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\bwidth\s*=").unwrap());
+
+// Keyed by content hash, so unchanged images are not re-encoded in watch mode
+static PROCESSED: LazyLock<Mutex<HashMap<String, Image>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Check if an URL refers to a file relative to the post
+pub fn is_local(url: &str) -> bool {
+    !url.is_empty()
+        && !url.starts_with('/')
+        && !url.starts_with('#')
+        && url::Url::parse(url) == Err(url::ParseError::RelativeUrlWithoutBase)
+}
+
+/// Writes an asset unless it exists. Assets are written to a temporary file first and
+/// renamed into place, so that an interrupted write does not leave a truncated asset.
+fn write_if_missing(
+    path: &Path,
+    assets: &Assets,
+    content: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<()> {
+    if !path.exists() {
+        if assets.verify {
+            anyhow::bail!("Missing asset {}", path.display());
+        }
+        log::debug!("Writing asset {}", path.display());
+        let mut tmp = tempfile::NamedTempFile::new_in(path.parent().unwrap())?;
+        tmp.write_all(&content()?)?;
+        // Temporary files are only readable by the owner
+        tmp.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+        tmp.persist(path)?;
+    }
+    Ok(())
+}
+
+fn hash_of(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn exif_str(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    let exif::Value::Ascii(ref v) = field.value else {
+        return None;
+    };
+    let s = String::from_utf8_lossy(v.first()?);
+    let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+fn photo_meta(exif: &exif::Exif) -> Option<PhotoMeta> {
+    // 2025:05:22 07:30:00 -> 2025-05-22T07:30:00
+    let taken = exif_str(exif, exif::Tag::DateTimeOriginal)
+        .or_else(|| exif_str(exif, exif::Tag::DateTime))
+        .map(|t| {
+            let t = match t.split_once(' ') {
+                Some((date, time)) => format!("{}T{}", date.replace(':', "-"), time),
+                None => t,
+            };
+            t + &exif_str(exif, exif::Tag::OffsetTimeOriginal).unwrap_or_default()
+        });
+
+    let make = exif_str(exif, exif::Tag::Make);
+    let model = exif_str(exif, exif::Tag::Model);
+    let camera = match (make, model) {
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model),
+    };
+    let lens = exif_str(exif, exif::Tag::LensModel);
+
+    if taken.is_none() && camera.is_none() && lens.is_none() {
+        return None;
+    }
+    Some(PhotoMeta {
+        taken,
+        camera,
+        lens,
+    })
+}
+
+/// Remove EXIF (including GPS location) and XMP metadata, only keeping the orientation so that
+/// browsers still display the image upright.
+fn strip_metadata(bytes: &[u8], exif: Option<&exif::Exif>) -> anyhow::Result<Vec<u8>> {
+    let Some(mut img) = DynImage::from_bytes(bytes.to_vec().into())? else {
+        return Ok(bytes.to_vec());
+    };
+
+    if let DynImage::Jpeg(ref mut jpeg) = img {
+        // XMP and IPTC
+        jpeg.remove_segments_by_marker(markers::APP1);
+        jpeg.remove_segments_by_marker(markers::APP13);
+    }
+
+    let orientation = exif
+        .and_then(|e| e.get_field(exif::Tag::Orientation, exif::In::PRIMARY))
+        .filter(|f| f.value.get_uint(0).is_some_and(|o| o != 1));
+    let stripped = match orientation {
+        Some(field) => {
+            let mut writer = exif::experimental::Writer::new();
+            writer.push_field(field);
+            let mut buf = Cursor::new(Vec::new());
+            writer.write(&mut buf, false)?;
+            Some(buf.into_inner().into())
+        }
+        None => None,
+    };
+    img.set_exif(stripped);
+
+    Ok(img.encoder().bytes().to_vec())
+}
+
+fn encode_webp(img: &DynamicImage, quality: f32) -> anyhow::Result<Vec<u8>> {
+    let rgba = img.to_rgba8();
+    let encoded = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+        .encode_simple(false, quality)
+        .map_err(|e| anyhow::anyhow!("Failed to encode WebP: {:?}", e))?;
+    Ok(encoded.to_vec())
+}
+
+fn encode_avif(img: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+        &mut buf,
+        AVIF_SPEED,
+        AVIF_QUALITY,
+    );
+    DynamicImage::ImageRgba8(img.to_rgba8()).write_with_encoder(encoder)?;
+    Ok(buf)
+}
+
+/// Copy an image into the asset directory under its content hash, generating responsive variants and
+/// a placeholder for raster images.
+pub fn process(path: &Path, assets: &Assets) -> anyhow::Result<Image> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read image {}: {}", path.display(), e))?;
+    let hash = hash_of(&bytes);
+
+    if let Some(processed) = PROCESSED.lock().unwrap().get(&hash) {
+        return Ok(processed.clone());
+    }
+
+    log::info!("Processing image {}", path.display());
+    if !assets.verify {
+        std::fs::create_dir_all(&assets.dir)?;
+    }
+    let url_of = |file: &str| format!("{}/{}", assets.url.trim_end_matches('/'), file);
+
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&bytes))
+        .ok();
+    let published = if assets.keep_exif {
+        bytes.clone()
+    } else {
+        strip_metadata(&bytes, exif.as_ref())?
+    };
+    let original = format!("{}.{}", hash_of(&published), ext);
+    write_if_missing(&assets.dir.join(&original), assets, || Ok(published))?;
+
+    let mut processed = Image {
+        src: url_of(&original),
+        size: None,
+        sources: Vec::new(),
+        placeholder: None,
+        photo: exif.as_ref().and_then(photo_meta),
+    };
+
+    match ext.as_str() {
+        // Vector images are served as-is
+        "svg" => {}
+        // Variants would lose the animation
+        "gif" => {
+            processed.size = Some(image::image_dimensions(path)?);
+        }
+        _ => {
+            let mut decoder = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()?
+                .into_decoder()?;
+            let orientation = decoder.orientation()?;
+            let mut decoded = DynamicImage::from_decoder(decoder)?;
+            decoded.apply_orientation(orientation);
+            let (width, height) = (decoded.width(), decoded.height());
+            processed.size = Some((width, height));
+
+            let widths: Vec<u32> = VARIANT_WIDTHS
+                .iter()
+                .copied()
+                .filter(|w| *w < width)
+                .chain(std::iter::once(width))
+                .collect();
+            widths.clone().into_par_iter().try_for_each(|w| {
+                let webp = assets.dir.join(format!("{}-{}w.webp", hash, w));
+                let avif = assets.dir.join(format!("{}-{}w.avif", hash, w));
+                if webp.exists() && avif.exists() {
+                    return Ok(());
+                }
+                let resized = decoded.resize(w, u32::MAX, FilterType::Lanczos3);
+                write_if_missing(&webp, assets, || encode_webp(&resized, WEBP_QUALITY))?;
+                write_if_missing(&avif, assets, || encode_avif(&resized))
+            })?;
+
+            for (mime, ext) in [("image/avif", "avif"), ("image/webp", "webp")] {
+                let srcset = widths
+                    .iter()
+                    .map(|w| format!("{} {}w", url_of(&format!("{}-{}w.{}", hash, w, ext)), w))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                processed.sources.push((mime, srcset));
+            }
+
+            let tiny = decoded.thumbnail(PLACEHOLDER_WIDTH, u32::MAX);
+            let tiny = encode_webp(&tiny, 30.0)?;
+            processed.placeholder = Some(format!(
+                "data:image/webp;base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(tiny)
+            ));
+        }
+    }
+
+    PROCESSED.lock().unwrap().insert(hash, processed.clone());
+    Ok(processed)
+}
+
+/// Render a processed image, wrapping it in `<picture>` if there are variants.
+///
+/// With `with_photo_meta`, EXIF metadata is exposed through `data-*` attributes.
+pub fn render(img: &Image, alt: &str, title: Option<&str>, with_photo_meta: bool) -> String {
+    let mut output = String::new();
+    if !img.sources.is_empty() {
+        output.push_str("<picture>");
+        for (mime, srcset) in &img.sources {
+            output.push_str(&format!("<source type=\"{}\" srcset=\"{}\">", mime, srcset));
+        }
+    }
+
+    output.push_str("<img src=\"");
+    escape_html(&mut output, &img.src).unwrap();
+    output.push_str("\" alt=\"");
+    escape_html(&mut output, alt).unwrap();
+    output.push('"');
+    if let Some(title) = title {
+        output.push_str(" title=\"");
+        escape_html(&mut output, title).unwrap();
+        output.push('"');
+    }
+    if let Some((w, h)) = img.size {
+        output.push_str(&format!(" width=\"{}\" height=\"{}\"", w, h));
+    }
+    if with_photo_meta && let Some(ref photo) = img.photo {
+        for (key, value) in [
+            ("taken", &photo.taken),
+            ("camera", &photo.camera),
+            ("lens", &photo.lens),
+        ] {
+            if let Some(value) = value {
+                output.push_str(&format!(" data-{}=\"", key));
+                escape_html(&mut output, value).unwrap();
+                output.push('"');
+            }
+        }
+    }
+    if let Some(ref placeholder) = img.placeholder {
+        output.push_str(&format!(
+            " style=\"background-image:url({});background-size:cover\"",
+            placeholder
+        ));
+    }
+    output.push_str(" loading=\"lazy\" decoding=\"async\">");
+
+    if !img.sources.is_empty() {
+        output.push_str("</picture>");
+    }
+    output
+}
+
+/// Rewrite local `src` of `<img>` tags in raw HTML, injecting the size if the width is not specified.
+pub fn rewrite_html(
+    html: &str,
+    process: impl Fn(&str) -> anyhow::Result<Image>,
+) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut last = 0;
+    for tag in IMG_TAG_RE.find_iter(html) {
+        let Some(src) = SRC_ATTR_RE.captures(tag.as_str()) else {
+            continue;
+        };
+        let url = src.get(1).unwrap();
+        if !is_local(url.as_str()) {
+            continue;
+        }
+
+        let img = process(url.as_str())?;
+        let tag_str = tag.as_str();
+        output.push_str(&html[last..tag.start()]);
+        output.push_str(&tag_str[..url.start()]);
+        escape_html(&mut output, &img.src).unwrap();
+        output.push('"');
+        if let Some((w, h)) = img.size
+            && !WIDTH_ATTR_RE.is_match(tag_str)
+        {
+            output.push_str(&format!(" width=\"{}\" height=\"{}\"", w, h));
+        }
+        output.push_str(&tag_str[url.end() + 1..]);
+        last = tag.end();
+    }
+    output.push_str(&html[last..]);
+    Ok(output)
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+static INCLUDE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\{\{#include\s+([^}\s]+)\s*\}\}$").unwrap());
+static ANCHOR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\bANCHOR(_END)?:\s*([\w-]+)").unwrap());
+
+enum Range<'a> {
+    Lines(Option<usize>, Option<usize>),
+    Anchor(&'a str),
+}
+
+/// Parses `path[:start[:end]]` or `path:anchor`, following mdBook's conventions:
+/// `:N` is a single line, `:N:` runs to the end of file, and `::N` starts from the beginning.
+fn parse_spec(spec: &str) -> anyhow::Result<(&str, Range<'_>)> {
+    let mut parts = spec.splitn(3, ':');
+    let path = parts.next().unwrap();
+    let parse_line = |s: &str| -> anyhow::Result<Option<usize>> {
+        if s.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(s.parse()?))
+        }
+    };
+
+    let range = match (parts.next(), parts.next()) {
+        (None, _) => Range::Lines(None, None),
+        (Some(anchor), None) if anchor.parse::<usize>().is_err() => Range::Anchor(anchor),
+        (Some(line), None) => {
+            let line = parse_line(line)?;
+            Range::Lines(line, line)
+        }
+        (Some(start), Some(end)) => Range::Lines(parse_line(start)?, parse_line(end)?),
+    };
+    Ok((path, range))
+}
+
+fn select<'a>(content: &'a str, range: &Range) -> anyhow::Result<Vec<&'a str>> {
+    match range {
+        Range::Lines(start, end) => {
+            // Lines are 1-indexed and inclusive on both ends
+            let start = start.unwrap_or(1).max(1) - 1;
+            let end = end.unwrap_or(usize::MAX);
+            Ok(content
+                .lines()
+                .skip(start)
+                .take(end.saturating_sub(start))
+                .collect())
+        }
+        Range::Anchor(name) => {
+            let mut selected = None;
+            for line in content.lines() {
+                let anchor = ANCHOR_RE.captures(line);
+                match anchor {
+                    Some(cap) if cap.get(2).unwrap().as_str() == *name => {
+                        if cap.get(1).is_none() {
+                            selected = Some(Vec::new());
+                        } else if let Some(selected) = selected {
+                            return Ok(selected);
+                        }
+                    }
+                    // Markers of other anchors are dropped from the output
+                    Some(_) => {}
+                    None => {
+                        if let Some(ref mut selected) = selected {
+                            selected.push(line);
+                        }
+                    }
+                }
+            }
+            Err(anyhow::anyhow!("Anchor {} not found or not closed", name))
+        }
+    }
+}
+
+pub struct Expanded {
+    pub content: String,
+    /// 0-based line number in the original content, for each line in the expanded content
+    pub source_lines: Vec<usize>,
+}
+
+impl Expanded {
+    fn push_line(&mut self, line: &str, source_line: usize) {
+        self.content.push_str(line);
+        self.content.push('\n');
+        self.source_lines.push(source_line);
+    }
+}
+
+/// Expand all `{{#include ...}}` directives occupying a whole line.
+///
+/// Inside a fenced code block, the directive is replaced verbatim by the included lines.
+/// Elsewhere, it becomes a fenced code block tagged with the file extension, so the
+/// highlighter can pick the syntax. Paths are relative to `base`, and all included
+/// files are recorded into `deps`.
+pub fn expand(content: &str, base: &Path, deps: &mut Vec<PathBuf>) -> anyhow::Result<Expanded> {
+    let mut output = Expanded {
+        content: String::with_capacity(content.len()),
+        source_lines: Vec::new(),
+    };
+    let mut fence: Option<&str> = None;
+
+    for (lineno, line) in content.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let fence_len = trimmed.len() - trimmed.trim_start_matches(['`', '~']).len();
+        if fence_len >= 3 {
+            let marker = &trimmed[..fence_len];
+            match fence {
+                None => fence = Some(marker),
+                Some(f) if marker.starts_with(f) && trimmed[fence_len..].trim().is_empty() => {
+                    fence = None
+                }
+                _ => {}
+            }
+        }
+
+        let Some(cap) = INCLUDE_RE.captures(line.trim()) else {
+            output.push_line(line, lineno);
+            continue;
+        };
+
+        let (path, range) = parse_spec(cap.get(1).unwrap().as_str())?;
+        let path = base.join(path);
+        let included = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Unable to include {}: {}", path.display(), e))?;
+        let selected = select(&included, &range)?;
+        deps.push(std::fs::canonicalize(&path)?);
+
+        if fence.is_some() {
+            for l in selected {
+                output.push_line(l, lineno);
+            }
+            continue;
+        }
+
+        // Use a fence longer than any backtick run in the included content
+        let longest = selected
+            .iter()
+            .filter_map(|l| {
+                let t = l.trim_start();
+                let len = t.len() - t.trim_start_matches('`').len();
+                (len > 0).then_some(len)
+            })
+            .max()
+            .unwrap_or(0);
+        let fence_str = "`".repeat(longest.max(2) + 1);
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        output.push_line(&format!("{}{}", fence_str, ext), lineno);
+        for l in selected {
+            output.push_line(l, lineno);
+        }
+        output.push_line(&fence_str, lineno);
+    }
+
+    Ok(output)
+}
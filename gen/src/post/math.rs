@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+pub type Macros = HashMap<String, String>;
+
+static DEFINITION_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\\(newcommand|renewcommand|providecommand|def|DeclareMathOperator)(\*?)").unwrap()
+});
+static LABEL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\\label\s*\{([^}]*)\}").unwrap());
+static REF_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\(eqref|ref)\s*\{([^}]*)\}").unwrap());
+
+/// Takes a single argument from the start of `s`: either a brace-delimited group or a control sequence.
+/// Returns the argument (without braces) and the remaining string.
+fn take_arg(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if let Some(inner) = s.strip_prefix('{') {
+        let mut depth = 1;
+        let mut escaped = false;
+        for (i, c) in inner.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((&inner[..i], &inner[i + 1..]));
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    } else if let Some(cs) = s.strip_prefix('\\') {
+        // Either a run of letters, or a single other character
+        let len = match cs.find(|c: char| !c.is_ascii_alphabetic()) {
+            Some(0) => cs.chars().next().map_or(0, char::len_utf8),
+            Some(len) => len,
+            None => cs.len(),
+        };
+        Some((&s[..len + 1], &cs[len..]))
+    } else {
+        None
+    }
+}
+
+/// Takes an optional `[...]` argument from the start of `s`.
+fn take_opt(s: &str) -> Option<(&str, &str)> {
+    let (inner, rest) = s.trim_start().strip_prefix('[')?.split_once(']')?;
+    Some((inner, rest))
+}
+
+/// Extract macro definitions from TeX source into `macros`, returning the source with definitions removed.
+///
+/// Supports `\newcommand`, `\renewcommand`, `\providecommand`, `\def` and `\DeclareMathOperator`.
+/// Definitions are stripped so they can be fed to KaTeX as macros instead, which avoids
+/// redefinition errors when a block is rendered with macros from earlier blocks.
+pub fn extract_macros(src: &str, macros: &mut Macros) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut rest = src;
+
+    while let Some(cap) = DEFINITION_RE.captures(rest) {
+        let whole = cap.get(0).unwrap();
+        // Skip prefixes of longer control sequences, e.g. \default
+        if rest[whole.end()..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic())
+        {
+            output.push_str(&rest[..whole.end()]);
+            rest = &rest[whole.end()..];
+            continue;
+        }
+
+        output.push_str(&rest[..whole.start()]);
+        let cmd = cap.get(1).unwrap().as_str();
+        let starred = !cap.get(2).unwrap().as_str().is_empty();
+        let after = &rest[whole.end()..];
+        let malformed = || anyhow::anyhow!("Malformed macro definition: {}", whole.as_str());
+
+        let (name, after) = take_arg(after).ok_or_else(malformed)?;
+        let name = name.trim();
+        let (body, after) = match cmd {
+            "def" => {
+                // Parameter text, e.g. \def\foo#1#2{...}
+                let params_end = after.find('{').ok_or_else(malformed)?;
+                take_arg(&after[params_end..]).ok_or_else(malformed)?
+            }
+            "DeclareMathOperator" => {
+                let (text, after) = take_arg(after).ok_or_else(malformed)?;
+                let op = if starred {
+                    "\\operatorname*"
+                } else {
+                    "\\operatorname"
+                };
+                let body = format!("{}{{{}}}", op, text);
+                if !name.starts_with('\\') {
+                    return Err(malformed());
+                }
+                macros.insert(name.to_owned(), body);
+                rest = after;
+                continue;
+            }
+            _ => {
+                let after = take_opt(after).map_or(after, |(_, after)| after);
+                if take_opt(after).is_some() {
+                    return Err(anyhow::anyhow!(
+                        "Optional arguments are not supported in macro {}",
+                        name
+                    ));
+                }
+                take_arg(after).ok_or_else(malformed)?
+            }
+        };
+
+        if !name.starts_with('\\') {
+            return Err(malformed());
+        }
+        if cmd != "providecommand" || !macros.contains_key(name) {
+            macros.insert(name.to_owned(), body.to_owned());
+        }
+        rest = after;
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Assigns equation numbers to `\label`s, in the order of display math blocks within a post.
+#[derive(Default)]
+pub struct Labels {
+    numbers: HashMap<String, usize>,
+}
+
+impl Labels {
+    pub fn scan(&mut self, display_math: &str) -> anyhow::Result<()> {
+        for cap in LABEL_RE.captures_iter(display_math) {
+            let key = cap.get(1).unwrap().as_str().trim().to_owned();
+            if self.numbers.contains_key(&key) {
+                return Err(anyhow::anyhow!("Duplicate label {}", key));
+            }
+            let next = self.numbers.len() + 1;
+            self.numbers.insert(key, next);
+        }
+        Ok(())
+    }
+}
+
+pub fn anchor(label: &str) -> String {
+    format!("eq-{}", label)
+}
+
+/// Renders math within a single post, with macros persisting across blocks.
+pub struct Renderer {
+    macros: Macros,
+    labels: Labels,
+}
+
+impl Renderer {
+    pub fn new(macros: Macros, labels: Labels) -> Self {
+        Self { macros, labels }
+    }
+
+    pub fn render(&mut self, src: &str, display: bool) -> anyhow::Result<String> {
+        let src = extract_macros(src, &mut self.macros)?;
+
+        // Substitute references and labels
+        let mut missing = None;
+        let src = REF_RE.replace_all(&src, |cap: &regex::Captures| {
+            let key = cap.get(2).unwrap().as_str().trim();
+            let Some(num) = self.labels.numbers.get(key) else {
+                missing = Some(key.to_owned());
+                return String::new();
+            };
+            let text = if cap.get(1).unwrap().as_str() == "eqref" {
+                format!("({})", num)
+            } else {
+                num.to_string()
+            };
+            format!("\\href{{#{}}}{{\\text{{{}}}}}", anchor(key), text)
+        });
+        if let Some(key) = missing {
+            return Err(anyhow::anyhow!("Reference to undefined label {}", key));
+        }
+
+        if !display && LABEL_RE.is_match(&src) {
+            return Err(anyhow::anyhow!("\\label is only allowed in display math"));
+        }
+        let keys: Vec<_> = LABEL_RE
+            .captures_iter(&src)
+            .map(|cap| cap.get(1).unwrap().as_str().trim())
+            .collect();
+        if keys.len() > 1 {
+            return Err(anyhow::anyhow!(
+                "Multiple labels in one equation: {}",
+                keys.join(", ")
+            ));
+        }
+        let label = keys.first().map(|key| key.to_string());
+        let src = LABEL_RE.replace_all(&src, |cap: &regex::Captures| {
+            let key = cap.get(1).unwrap().as_str().trim();
+            format!("\\tag{{{}}}", self.labels.numbers[key])
+        });
+
+        let mut opts = katex::Opts::builder();
+        opts.display_mode(display)
+            .output_type(katex::OutputType::HtmlAndMathml)
+            .macros(self.macros.clone())
+            .trust(true);
+        if display {
+            opts.max_size(50f64);
+        }
+        let rendered = katex::render_with_opts(&src, opts.build().unwrap())?;
+
+        Ok(match label {
+            Some(label) => format!(
+                "<span class=\"equation\" id=\"{}\">{}</span>",
+                anchor(&label),
+                rendered
+            ),
+            None => rendered,
+        })
+    }
+}
@@ -1,4 +1,6 @@
+use std::cell::RefCell;
 use std::iter::Iterator;
+use std::path::{Path, PathBuf};
 
 use syntect::dumps::from_uncompressed_data;
 use syntect::easy::HighlightLines;
@@ -7,20 +9,52 @@ use syntect::html::{IncludeBackground, append_highlighted_html_for_styled_line};
 use syntect::parsing::{SyntaxReference, SyntaxSet};
 use syntect::util::LinesWithEndings;
 
+use super::Site;
+use super::{
+    abbr, callout, chart, cite, diagram, figure, gpx, image, math, series, spoiler, theorem, xref,
+};
+use crate::ruby;
+
 pub struct ParsedMarkdown {
     pub metadata: PartialMetadata,
     pub html: String,
     pub plain: String,
+    /// Ranges of spoilers in `plain`, in UTF-16 code units
+    pub spoilers: Vec<(usize, usize)>,
+    /// Files other than the post itself that were read during parsing
+    pub deps: Vec<PathBuf>,
+    /// Abbreviations used, with their expansions
+    pub abbrs: Vec<(String, String)>,
 }
 
 pub struct PartialMetadata {
     pub title: String,
     pub tags: Vec<String>,
+    /// Posts publishing in the future are withheld until then
     pub force_publish_time: Option<chrono::DateTime<chrono::FixedOffset>>,
     pub force_update_time: Option<chrono::DateTime<chrono::FixedOffset>>,
-    pub hidden: bool,
+    pub visibility: super::Visibility,
     pub wip: bool,
     pub legacy: bool,
+    /// Spoiler warning shown before the content
+    pub spoilers: Option<String>,
+    pub katex_macros: math::Macros,
+    /// Expose EXIF metadata of local photos
+    pub photo_meta: bool,
+    /// BibTeX / BibLaTeX file of this post, in addition to the site-wide one
+    pub bibliography: Option<String>,
+    /// Overrides the site-wide citation style
+    pub citation_style: Option<String>,
+    pub series: Option<series::SeriesRef>,
+}
+
+/// Environment for parsing a single post
+pub struct Context<'a> {
+    /// Directory of the post, against which relative paths are resolved
+    pub base: &'a Path,
+    pub site: &'a Site,
+    /// Language of the post, for localized labels
+    pub lang: &'a str,
 }
 
 fn highlight_code_html(
@@ -50,11 +84,20 @@ fn highlight_code_html(
     Ok(output)
 }
 
-pub fn parse(input: &str) -> anyhow::Result<ParsedMarkdown> {
+/// A post with only its frontmatter parsed, so that posts left out of the build are
+/// never rendered
+pub struct Source {
+    pub metadata: PartialMetadata,
+    content: String,
+    /// Line number of the start of content within the file, for diagnostics
+    content_line: usize,
+}
+
+pub fn split(input: &str, ctx: &Context) -> anyhow::Result<Source> {
     let input = input.trim();
 
     // Split frontmatter
-    let (metadata, content) = if input.starts_with("---\n") {
+    let (mut metadata, content) = if input.starts_with("---\n") {
         // Contains frontmatter
         if let Some((fm, content)) = input[4..].split_once("\n---") {
             // TODO: actually it's \n---(\n|$)
@@ -69,7 +112,186 @@ pub fn parse(input: &str) -> anyhow::Result<ParsedMarkdown> {
         return Err(anyhow::anyhow!("No frontmatter found"));
     };
 
-    let parser = pulldown_cmark::Parser::new_ext(content.trim(), pulldown_cmark::Options::all());
+    metadata.tags = ctx.site.tags.normalize(metadata.tags);
+
+    // Line number of the start of content within input, for diagnostics
+    let content_line = input[..input.len() - content.trim_start().len()]
+        .matches('\n')
+        .count();
+
+    Ok(Source {
+        metadata,
+        content: content.to_owned(),
+        content_line,
+    })
+}
+
+/// Renders a post, reading included files and writing local images into assets
+pub fn render(source: Source, ctx: &Context) -> anyhow::Result<ParsedMarkdown> {
+    render_inner(source, ctx, false)
+}
+
+/// Title and plain text of a post as rendered by [`render`], without rendering HTML. Local
+/// images and math are left alone and errors are not logged, so this has no side effects.
+pub fn plain_text(source: Source, ctx: &Context) -> anyhow::Result<String> {
+    let title = source.metadata.title.clone();
+    let parsed = render_inner(source, ctx, true)?;
+    Ok(title + "\n" + &parsed.plain)
+}
+
+fn render_inner(source: Source, ctx: &Context, plain_only: bool) -> anyhow::Result<ParsedMarkdown> {
+    let Source {
+        metadata,
+        content,
+        content_line,
+    } = source;
+
+    let mut deps = Vec::new();
+    let expanded = super::include::expand(content.trim(), ctx.base, &mut deps)?;
+    let mut defined = abbr::Abbrs::new();
+    let content = abbr::extract(&expanded.content, &mut defined);
+    let abbrs = abbr::merge(&ctx.site.glossary, defined);
+    let line_of = |offset: usize| -> usize {
+        let line = content[..offset].matches('\n').count();
+        content_line + expanded.source_lines[line] + 1
+    };
+
+    let events: Vec<_> = pulldown_cmark::Parser::new_ext(&content, pulldown_cmark::Options::all())
+        .into_offset_iter()
+        .collect();
+
+    let photo_meta = metadata.photo_meta;
+
+    // Shared with the rendering coroutine
+    let deps = RefCell::new(deps);
+    let errors = RefCell::new(Vec::new());
+    let report = |line: usize, e: anyhow::Error| {
+        if !plain_only {
+            log::error!("Line {}: {}", line, e);
+        }
+        errors.borrow_mut().push(format!("line {}: {}", line, e));
+    };
+    let report_at = |offset: usize, e: anyhow::Error| report(line_of(offset), e);
+
+    // Number equations beforehand, so that forward references can be resolved
+    let mut labels = math::Labels::default();
+    for (ev, range) in &events {
+        if let pulldown_cmark::Event::DisplayMath(s) = ev
+            && let Err(e) = labels.scan(s.as_ref())
+        {
+            report_at(range.start, e);
+        }
+    }
+    let mut macros = ctx.site.katex_macros.clone();
+    macros.extend(metadata.katex_macros.clone());
+    let mut math = math::Renderer::new(macros, labels);
+    let resolve_image = |url: &str| -> anyhow::Result<image::Image> {
+        let assets = ctx.site.assets.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("No asset directory configured for local image {}", url)
+        })?;
+        let path = ctx.base.join(url);
+        let processed = image::process(&path, assets)?;
+        deps.borrow_mut().push(std::fs::canonicalize(&path)?);
+        Ok(processed)
+    };
+
+    let load_data = |file: &str| -> anyhow::Result<String> {
+        let path = ctx.base.join(file);
+        let data = std::fs::read_to_string(&path)?;
+        deps.borrow_mut().push(std::fs::canonicalize(&path)?);
+        Ok(data)
+    };
+
+    // Figures and theorems are numbered beforehand as well
+    let mut xrefs = xref::Xrefs::default();
+    let events = theorem::transform(events, &mut xrefs, ctx.lang, report_at);
+    let events = callout::transform(events, ctx.lang);
+    let events = spoiler::transform(events);
+    let events = figure::transform(events, &mut xrefs, ctx.lang, report_at);
+    let events = diagram::transform(events, report_at);
+    let events = chart::transform(events, &ctx.site.palette, load_data, report_at);
+    let events = gpx::transform(events, &ctx.site.palette, ctx.lang, load_data, report_at);
+    let events = xref::resolve(events, &xrefs, ctx.lang, report_at);
+    let bib = cite::Bibliography {
+        post: match metadata.bibliography {
+            Some(ref file) => Some(cite::parse_bib(&load_data(file)?)?),
+            None => None,
+        },
+        site: &ctx.site.bibliography,
+        style: cite::style(
+            metadata
+                .citation_style
+                .as_deref()
+                .or(ctx.site.citation_style.as_deref())
+                .unwrap_or(cite::DEFAULT_STYLE),
+        )?,
+    };
+    let events = cite::transform(events, &bib, ctx.lang, report_at);
+    let events = ruby::transform(events, report_at);
+    let (events, used_abbrs) = abbr::transform(events, &abbrs);
+
+    // Generate plaintext
+    let mut plain = String::new();
+    let mut spoilers = Vec::new();
+    let mut spoiler_start = 0;
+    let mut spoiler_depth = 0;
+    for (ev, _) in &events {
+        use pulldown_cmark::{Event, Tag, TagEnd};
+        match ev {
+            Event::Start(t) => {
+                plain += match t {
+                    Tag::Heading { .. } => "\n",
+                    Tag::Item => "- ",
+                    _ => "",
+                }
+            }
+            Event::End(t) => {
+                plain += match t {
+                    TagEnd::Paragraph
+                    | TagEnd::BlockQuote(_)
+                    | TagEnd::CodeBlock
+                    | TagEnd::Heading(_)
+                    | TagEnd::Item => "\n",
+                    _ => "",
+                }
+            }
+            Event::Text(s)
+            | Event::Code(s)
+            | Event::DisplayMath(s)
+            | Event::InlineMath(s)
+            | Event::FootnoteReference(s) => plain += s.as_ref(),
+            Event::SoftBreak => plain += " ",
+            Event::HardBreak => plain += "\n",
+            Event::Rule => plain += "---\n",
+            Event::TaskListMarker(c) => plain += if *c { "[x] " } else { "[ ] " },
+            Event::Html(s)
+                if s.as_ref() == figure::CAPTION_END
+                    || callout::TITLE_ENDS.contains(&s.as_ref()) =>
+            {
+                plain += "\n"
+            }
+            Event::Html(s) | Event::InlineHtml(s)
+                if [spoiler::INLINE_START, spoiler::BLOCK_START].contains(&s.as_ref()) =>
+            {
+                // Nested spoilers are covered by the outermost one
+                if spoiler_depth == 0 {
+                    spoiler_start = plain.encode_utf16().count();
+                }
+                spoiler_depth += 1;
+            }
+            Event::Html(s) | Event::InlineHtml(s)
+                if spoiler_depth > 0
+                    && [spoiler::INLINE_END, spoiler::BLOCK_END].contains(&s.as_ref()) =>
+            {
+                spoiler_depth -= 1;
+                if spoiler_depth == 0 {
+                    spoilers.push((spoiler_start, plain.encode_utf16().count()));
+                }
+            }
+            Event::Html(_) | Event::InlineHtml(_) => {}
+        }
+    }
+
     let mapped = std::pin::pin!(
         #[coroutine]
         static move || {
@@ -81,12 +303,15 @@ pub fn parse(input: &str) -> anyhow::Result<ParsedMarkdown> {
             let theme = &ts.themes["Solarized (dark)"];
             let mut codeblock = String::new();
             let mut in_codeblock = None;
+            // (URL, title, alt text) of a local image
+            let mut in_image: Option<(pulldown_cmark::CowStr, pulldown_cmark::CowStr, String)> =
+                None;
 
             for syn in ss.syntaxes() {
                 log::debug!("Supported syntax: {}", syn.name);
             }
 
-            for event in parser.into_iter() {
+            for (event, range) in events {
                 match event {
                     Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang)))
                         if lang.as_ref() != ""
@@ -113,37 +338,40 @@ pub fn parse(input: &str) -> anyhow::Result<ParsedMarkdown> {
                     Event::Text(text) if in_codeblock.is_some() => {
                         codeblock.push_str(text.as_ref());
                     }
-                    Event::DisplayMath(s) => {
-                        let opts = katex::Opts::builder()
-                            .display_mode(true)
-                            .output_type(katex::OutputType::HtmlAndMathml)
-                            .max_size(50f64)
-                            .build()
-                            .unwrap();
-                        yield match katex::render_with_opts(s.as_ref(), opts) {
-                            Ok(r) => Event::Html(r.into()),
-                            Err(e) => {
-                                log::warn!("Failed to render math: {}", e);
-                                log::warn!("Math source: {}", s);
-                                Event::DisplayMath(s)
+                    Event::Start(Tag::Image {
+                        dest_url, title, ..
+                    }) if image::is_local(&dest_url) => {
+                        in_image = Some((dest_url, title, String::new()));
+                    }
+                    Event::End(TagEnd::Image) if let Some((url, title, alt)) = in_image.take() => {
+                        let title = (!title.is_empty()).then_some(title.as_ref());
+                        match resolve_image(&url) {
+                            Ok(img) => {
+                                let rendered = image::render(&img, &alt, title, photo_meta);
+                                yield Event::Html(rendered.into());
                             }
+                            Err(e) => report(line_of(range.start), e),
                         }
                     }
-                    Event::InlineMath(s) => {
-                        let opts = katex::Opts::builder()
-                            .display_mode(false)
-                            .output_type(katex::OutputType::HtmlAndMathml)
-                            .build()
-                            .unwrap();
-                        yield match katex::render_with_opts(s.as_ref(), opts) {
-                            Ok(r) => Event::Html(r.into()),
-                            Err(e) => {
-                                log::warn!("Failed to render math: {}", e);
-                                log::warn!("Math source: {}", s);
-                                Event::InlineMath(s)
-                            }
+                    Event::Text(s) | Event::Code(s) if in_image.is_some() => {
+                        in_image.as_mut().unwrap().2.push_str(s.as_ref());
+                    }
+                    // Formatting within alt text
+                    _ if in_image.is_some() => {}
+                    Event::Html(s) | Event::InlineHtml(s) if s.contains("<img") => {
+                        match image::rewrite_html(&s, resolve_image) {
+                            Ok(r) => yield Event::Html(r.into()),
+                            Err(e) => report(line_of(range.start), e),
                         }
                     }
+                    Event::DisplayMath(s) => match math.render(s.as_ref(), true) {
+                        Ok(r) => yield Event::Html(r.into()),
+                        Err(e) => report(line_of(range.start), e),
+                    },
+                    Event::InlineMath(s) => match math.render(s.as_ref(), false) {
+                        Ok(r) => yield Event::Html(r.into()),
+                        Err(e) => report(line_of(range.start), e),
+                    },
                     e => {
                         assert!(in_codeblock.is_none());
                         yield e;
@@ -153,60 +381,91 @@ pub fn parse(input: &str) -> anyhow::Result<ParsedMarkdown> {
         }
     );
     let mut html = String::new();
-    pulldown_cmark::html::push_html(&mut html, std::iter::from_coroutine(mapped));
+    if !plain_only {
+        pulldown_cmark::html::push_html(&mut html, std::iter::from_coroutine(mapped));
+    }
 
-    // Generate plaintext
-    let mut plain = String::new();
-    for ev in pulldown_cmark::Parser::new_ext(content.trim(), pulldown_cmark::Options::all()) {
-        use pulldown_cmark::{Event, Tag, TagEnd};
-        match ev {
-            Event::Start(t) => {
-                plain += match t {
-                    Tag::Heading { .. } => "\n",
-                    Tag::Item => "- ",
-                    _ => "",
-                }
-            }
-            Event::End(t) => {
-                plain += match t {
-                    TagEnd::Paragraph
-                    | TagEnd::BlockQuote(_)
-                    | TagEnd::CodeBlock
-                    | TagEnd::Heading(_)
-                    | TagEnd::Item => "\n",
-                    _ => "",
-                }
-            }
-            Event::Text(s)
-            | Event::Code(s)
-            | Event::DisplayMath(s)
-            | Event::InlineMath(s)
-            | Event::FootnoteReference(s) => plain += s.as_ref(),
-            Event::SoftBreak => plain += " ",
-            Event::HardBreak => plain += "\n",
-            Event::Rule => plain += "---\n",
-            Event::TaskListMarker(c) => plain += if c { "[x] " } else { "[ ] " },
-            Event::Html(_) | Event::InlineHtml(_) => {}
-        }
+    let errors = errors.borrow();
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!("Failed to render:\n{}", errors.join("\n")));
     }
 
     Ok(ParsedMarkdown {
         metadata,
         html,
         plain,
+        spoilers,
+        deps: deps.take(),
+        abbrs: used_abbrs
+            .into_iter()
+            .map(|term| {
+                let expansion = abbrs[&term].clone();
+                (term, expansion)
+            })
+            .collect(),
     })
 }
 
+/// Approximate plain text of a post source, i.e. its title and the text of its content.
+///
+/// Unlike [`render`], includes are not expanded and nothing is rendered, so this is cheap
+/// and side-effect free enough to run on every revision of a post.
+pub fn source_text(input: &str) -> String {
+    use pulldown_cmark::{Event, TagEnd};
+
+    let input = input.trim();
+    let (fm, content) = input
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.split_once("\n---"))
+        .unwrap_or(("", input));
+
+    let mut text: String = fm
+        .lines()
+        .filter_map(|line| line.strip_prefix("title:"))
+        .map(|title| title.trim().to_owned() + "\n")
+        .collect();
+    for ev in pulldown_cmark::Parser::new_ext(content, pulldown_cmark::Options::all()) {
+        match ev {
+            Event::Text(s)
+            | Event::Code(s)
+            | Event::DisplayMath(s)
+            | Event::InlineMath(s)
+            | Event::Html(s)
+            | Event::InlineHtml(s) => text += s.as_ref(),
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::BlockQuote(_)
+                | TagEnd::TableRow
+                | TagEnd::TableHead,
+            ) => text += "\n",
+            _ => {}
+        }
+    }
+    text
+}
+
 fn parse_frontmatter(fm: &str) -> anyhow::Result<PartialMetadata> {
     let mut result = PartialMetadata {
         title: String::new(),
         tags: Vec::new(),
         force_publish_time: None,
         force_update_time: None,
-        hidden: false,
+        visibility: super::Visibility::Public,
         wip: false,
         legacy: false,
+        spoilers: None,
+        katex_macros: math::Macros::new(),
+        photo_meta: false,
+        bibliography: None,
+        citation_style: None,
+        series: None,
     };
+    let mut visibility = None;
+    let mut hidden = None;
 
     for line in fm.trim().lines() {
         if let Some((key, value)) = line.split_once(":") {
@@ -219,21 +478,46 @@ fn parse_frontmatter(fm: &str) -> anyhow::Result<PartialMetadata> {
                 "tags" => {
                     result.tags = value.split(",").map(str::trim).map(str::to_owned).collect();
                 }
-                "force_publish_time" => {
+                "force_publish_time" | "publish_at" => {
                     result.force_publish_time = Some(chrono::DateTime::parse_from_rfc3339(value)?);
                 }
                 "force_update_time" => {
                     result.force_update_time = Some(chrono::DateTime::parse_from_rfc3339(value)?);
                 }
+                "visibility" => {
+                    visibility = Some(super::Visibility::parse(value)?);
+                }
                 "hidden" => {
-                    result.hidden = value.parse()?;
+                    hidden = Some(value.parse()?);
                 }
                 "wip" => {
                     result.wip = value.parse()?;
                 }
+                "spoilers" => {
+                    result.spoilers = Some(value.to_owned());
+                }
                 "legacy" => {
                     result.legacy = value.parse()?;
                 }
+                "photo_meta" => {
+                    result.photo_meta = value.parse()?;
+                }
+                "bibliography" => {
+                    result.bibliography = Some(value.to_owned());
+                }
+                "citation_style" => {
+                    cite::style(value)?;
+                    result.citation_style = Some(value.to_owned());
+                }
+                "series" => {
+                    result.series = Some(series::parse_ref(value)?);
+                }
+                "macros" => {
+                    let rest = math::extract_macros(value, &mut result.katex_macros)?;
+                    if !rest.trim().is_empty() {
+                        return Err(anyhow::anyhow!("Unexpected content in macros: {}", rest));
+                    }
+                }
                 _ => {
                     return Err(anyhow::anyhow!("Unsupported frontmatter key: {}", key));
                 }
@@ -243,5 +527,15 @@ fn parse_frontmatter(fm: &str) -> anyhow::Result<PartialMetadata> {
         }
     }
 
+    result.visibility = match (visibility, hidden) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!("Both visibility and hidden given"));
+        }
+        (Some(visibility), None) => visibility,
+        // Predates visibility, hidden posts are unlisted
+        (None, Some(true)) => super::Visibility::Unlisted,
+        (None, _) => super::Visibility::Public,
+    };
+
     Ok(result)
 }
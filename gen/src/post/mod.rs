@@ -1,14 +1,45 @@
-use std::{collections::HashMap, os::unix::prelude::OsStrExt, path::Path, sync::LazyLock};
+use std::{
+    collections::{HashMap, HashSet},
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    sync::Mutex,
+};
 
 use chrono::TimeZone;
 use git2::{DiffFindOptions, DiffOptions, Oid, Sort};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{font::TitleResp, post::md::ParsedMarkdown};
 
+mod abbr;
+mod callout;
+mod chart;
+mod cite;
+mod collate;
+mod diagram;
+mod figure;
+mod gpx;
+mod image;
+mod include;
+mod math;
 mod md;
+mod related;
+mod revision;
+mod series;
+mod spoiler;
+mod tags;
+mod theorem;
+mod timestamps;
+mod xref;
+
+pub use abbr::GlossaryEntry;
+pub use image::Assets;
+pub use revision::Revision;
+pub use series::Series;
+pub use tags::TagInfo;
 
 #[derive(Debug, Serialize, ts_rs::TS)]
 #[ts(export)]
@@ -17,9 +48,74 @@ pub struct Post {
     pub metadata: Metadata,
     pub html: String,
     pub plain: String,
+    /// Ranges of spoilers in `plain`, in UTF-16 code units
+    pub spoilers: Vec<(usize, usize)>,
+    /// Other files this post is rendered from, e.g. included sources
+    #[serde(skip)]
+    #[ts(skip)]
+    pub deps: Vec<PathBuf>,
+    /// Abbreviations used in this post, with their expansions
+    #[serde(skip)]
+    #[ts(skip)]
+    pub abbrs: Vec<(String, String)>,
+    /// Series this post belongs to, as given in the frontmatter
+    #[serde(skip)]
+    #[ts(skip)]
+    pub series: Option<series::SeriesRef>,
+    /// Title as sorted by, see [`collate::sort_text`]
+    #[serde(skip)]
+    #[ts(skip)]
+    pub sort_title: String,
 }
 
-type DT = chrono::DateTime<chrono::FixedOffset>;
+pub type DT = chrono::DateTime<chrono::FixedOffset>;
+
+/// Fixed build time from `SOURCE_DATE_EPOCH`, in UTC
+static SOURCE_DATE: LazyLock<Option<DT>> = LazyLock::new(|| {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+    let time = epoch
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0));
+    if time.is_none() {
+        log::warn!("Invalid SOURCE_DATE_EPOCH: {}", epoch);
+    }
+    Some(time?.fixed_offset())
+});
+
+/// Current time of the build, fixed by `SOURCE_DATE_EPOCH` if set so that uncommitted
+/// posts build reproducibly
+pub fn now() -> DT {
+    SOURCE_DATE.unwrap_or_else(|| chrono::Local::now().fixed_offset())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ts_rs::TS)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Only built with `--drafts`
+    Draft,
+    /// Reachable by URL, but left out of lists, feeds and search
+    Unlisted,
+    #[default]
+    Public,
+}
+
+impl Visibility {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "draft" => Ok(Visibility::Draft),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "public" => Ok(Visibility::Public),
+            _ => Err(anyhow::anyhow!("Unknown visibility: {}", s)),
+        }
+    }
+
+    /// Whether the post shows up in lists, feeds, search and other aggregations
+    pub fn is_listed(self) -> bool {
+        self == Visibility::Public
+    }
+}
 
 #[derive(Debug, Serialize, ts_rs::TS)]
 #[serde(rename_all = "lowercase")]
@@ -27,16 +123,191 @@ pub struct Metadata {
     pub id: String,
     pub lang: String,
     pub title: String,
+    /// Collation key of the title, filled in by [`Site::link_collation`]
+    pub title_key: String,
     pub tags: Vec<String>,
     #[ts(type = "string")]
     pub publish_time: DT,
     #[ts(as = "Option<String>")]
     pub update_time: Option<DT>,
     pub title_outline: TitleResp,
-    pub hidden: bool,
+    pub visibility: Visibility,
     pub wip: bool,
     pub legacy: bool,
+    pub spoilers: Option<String>,
     pub img: Option<String>,
+    /// Filled in by [`Site::link_series`]
+    pub series: Option<series::SeriesNav>,
+    /// Filled in by [`link_related`], most related first
+    pub related: Vec<related::Related>,
+    /// Commits counting as updates, following renames, newest first
+    pub history: Vec<revision::Revision>,
+}
+
+/// Site-wide configuration. Paths are relative to the configuration file.
+#[derive(Deserialize, Default)]
+pub struct SiteConfig {
+    /// TeX file with macro definitions shared by all posts
+    katex_macros: Option<PathBuf>,
+    /// Abbreviation definitions shared by all posts, in the same `*[TERM]: expansion` syntax
+    glossary: Option<PathBuf>,
+    /// CSS colors of chart series
+    #[serde(default)]
+    palette: Vec<String>,
+    /// BibTeX / BibLaTeX file cited by posts
+    bibliography: Option<PathBuf>,
+    /// Name of the CSL style for citations, e.g. `ieee` or `apa`
+    citation_style: Option<String>,
+    /// Series titles, by series ID and language
+    #[serde(default)]
+    series: series::Titles,
+    /// YAML tag registry, mapping slugs to localized names, descriptions, parents and aliases
+    tags: Option<PathBuf>,
+    /// Commits changing fewer characters of a post's rendered text than this, ignoring
+    /// whitespace, do not count as updates of the post. Commits leaving the text unchanged
+    /// never count, even with the default of 0.
+    #[serde(default)]
+    update_threshold: usize,
+}
+
+/// Site-wide resources shared by all posts
+#[derive(Default)]
+pub struct Site {
+    katex_macros: math::Macros,
+    glossary: abbr::Abbrs,
+    palette: Vec<String>,
+    bibliography: hayagriva::Library,
+    citation_style: Option<String>,
+    series: series::Titles,
+    tags: tags::Registry,
+    update_threshold: usize,
+    /// Build draft posts
+    pub drafts: bool,
+    /// Withheld posts publishing in the future, by filename
+    scheduled: Mutex<HashMap<String, DT>>,
+    /// Timestamp lockfile, read if the git history is unavailable
+    pub timestamps: Option<PathBuf>,
+    /// Write the timestamp lockfile after walking the full history
+    pub write_timestamps: bool,
+    /// Output of local images. Posts referencing local images fail to build without this.
+    pub assets: Option<Assets>,
+}
+
+impl Site {
+    pub fn load(cfg_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let cfg_path = cfg_path.as_ref();
+        let cfg: SiteConfig = serde_json::from_reader(std::fs::File::open(cfg_path)?)?;
+        let base = cfg_path.parent().unwrap_or(Path::new("."));
+        let mut site = Site {
+            palette: cfg.palette.clone(),
+            citation_style: cfg.citation_style.clone(),
+            series: cfg.series.clone(),
+            update_threshold: cfg.update_threshold,
+            ..Default::default()
+        };
+
+        if let Some(ref name) = cfg.citation_style {
+            cite::style(name)?;
+        }
+
+        if let Some(ref p) = cfg.katex_macros {
+            let preamble = std::fs::read_to_string(base.join(p))?;
+            let rest = math::extract_macros(&preamble, &mut site.katex_macros)?;
+            if !rest.trim().is_empty() {
+                log::warn!("Ignoring non-definition content in {}", p.display());
+            }
+        }
+
+        if let Some(ref p) = cfg.glossary {
+            let content = std::fs::read_to_string(base.join(p))?;
+            let rest = abbr::extract(&content, &mut site.glossary);
+            if !rest.trim().is_empty() {
+                log::warn!("Ignoring non-definition content in {}", p.display());
+            }
+        }
+
+        if let Some(ref p) = cfg.bibliography {
+            let source = std::fs::read_to_string(base.join(p))?;
+            site.bibliography = cite::parse_bib(&source)?;
+        }
+
+        if let Some(ref p) = cfg.tags {
+            let source = std::fs::read_to_string(base.join(p))?;
+            site.tags = tags::Registry::parse(&source)
+                .map_err(|e| anyhow::anyhow!("{}: {}", p.display(), e))?;
+        }
+
+        Ok(site)
+    }
+
+    /// Glossary of abbreviations used across posts
+    pub fn glossary<'a>(&self, posts: impl Iterator<Item = &'a Post>) -> Vec<GlossaryEntry> {
+        abbr::glossary(&self.glossary, posts)
+    }
+
+    /// All series, with their parts in order
+    pub fn series<'a>(&self, posts: impl Iterator<Item = &'a Post>) -> Vec<Series> {
+        series::collect(&self.series, posts)
+    }
+
+    /// Whether a post is part of the build. Drafts and posts publishing in the future
+    /// are left out unless drafts are enabled. The latter are recorded for
+    /// [`Site::next_scheduled`].
+    ///
+    /// Checked before rendering, so that left out posts do not leak into any output.
+    pub fn includes(&self, filename: &str, visibility: Visibility, publish_time: DT) -> bool {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        scheduled.remove(filename);
+        if self.drafts {
+            return true;
+        }
+        // Drafts are never published, whatever their publish time
+        if visibility == Visibility::Draft {
+            return false;
+        }
+
+        // Not `now()`, so that scheduled builds publish posts even if `SOURCE_DATE_EPOCH` is
+        // pinned to the last commit
+        if publish_time > chrono::Local::now() {
+            log::info!("Withholding {} until {}", filename, publish_time);
+            scheduled.insert(filename.to_owned(), publish_time);
+            return false;
+        }
+        true
+    }
+
+    /// The earliest withheld post publishing in the future, with its publish time
+    pub fn next_scheduled(&self) -> Option<(String, DT)> {
+        let scheduled = self.scheduled.lock().unwrap();
+        scheduled
+            .iter()
+            .min_by_key(|(filename, time)| (**time, *filename))
+            .map(|(filename, time)| (filename.clone(), *time))
+    }
+
+    /// Registered tags and tags used by posts, with post statistics
+    pub fn tags<'a>(&self, posts: impl Iterator<Item = &'a Post> + Clone) -> Vec<TagInfo> {
+        let keys = collate::keys(&self.tags, posts.clone());
+        tags::collect(&self.tags, &keys, posts)
+    }
+
+    /// Fills in collation keys of titles in `posts`
+    pub fn link_collation(&self, posts: &mut HashMap<String, Post>) {
+        let keys = collate::keys(&self.tags, posts.values());
+        for post in posts.values_mut() {
+            post.metadata.title_key = collate::key(&keys, &post.metadata.lang, &post.sort_title);
+        }
+    }
+
+    /// Fills in series navigation in the metadata of `posts`
+    pub fn link_series(&self, posts: &mut HashMap<String, Post>) {
+        series::link(&self.series, posts)
+    }
+}
+
+/// Fills in related posts in the metadata of `posts`
+pub fn link_related(posts: &mut HashMap<String, Post>) {
+    related::link(posts)
 }
 
 static FILENAME_RE: LazyLock<Regex> =
@@ -63,19 +334,13 @@ fn find_image(html: &str) -> Option<String> {
 fn serialize_single(
     filename: &str,
     pre: ParsedMarkdown,
+    publish_time: DT,
     creation: Option<DT>,
     update: Option<DT>,
+    history: Vec<revision::Revision>,
     title_font: &ttf_parser::Face,
 ) -> anyhow::Result<Post> {
     log::info!("Processing {}", filename);
-    let publish_time = pre
-        .metadata
-        .force_publish_time
-        .or(creation)
-        .unwrap_or_else(|| {
-            log::warn!("Unpublished post: {}", filename);
-            chrono::Local::now().fixed_offset()
-        });
     // TODO: check filename for publish time, check if they match
     let reduced_update_time = update.and_then(|t| {
         if t == creation.unwrap() {
@@ -93,43 +358,86 @@ fn serialize_single(
     Ok(Post {
         html: pre.html,
         plain: pre.plain,
+        spoilers: pre.spoilers,
+        deps: pre.deps,
+        abbrs: pre.abbrs,
+        series: pre.metadata.series,
+        sort_title: collate::sort_text(&pre.metadata.title, lang),
         metadata: Metadata {
             id: id.to_owned(),
             lang: lang.to_owned(),
-            title: pre.metadata.title,
+            title: crate::ruby::base_text(&pre.metadata.title)?,
+            title_key: String::new(),
             tags: pre.metadata.tags,
-            hidden: pre.metadata.hidden,
+            visibility: pre.metadata.visibility,
             wip: pre.metadata.wip,
             legacy: pre.metadata.legacy,
+            spoilers: pre.metadata.spoilers,
             publish_time,
             update_time,
             title_outline,
             img,
+            series: None,
+            related: Vec::new(),
+            history,
         },
     })
 }
 
 struct LatestFile {
-    content: ParsedMarkdown,
+    content: md::Source,
     created: Option<DT>,
     updated: Option<DT>,
+    history: Vec<revision::Revision>,
 }
 
 impl LatestFile {
-    fn new(content: ParsedMarkdown) -> Self {
+    fn new(content: md::Source) -> Self {
         Self {
             content,
             created: None,
             updated: None,
+            history: Vec::new(),
         }
     }
 
-    fn serialize(self, filename: &str, title_font: &ttf_parser::Face) -> anyhow::Result<Post> {
+    fn publish_time(&self, filename: &str) -> DT {
+        self.content
+            .metadata
+            .force_publish_time
+            .or(self.created)
+            .unwrap_or_else(|| {
+                log::warn!("Unpublished post: {}", filename);
+                now()
+            })
+    }
+
+    /// Renders the post, see [`md::render`]
+    fn serialize(
+        mut self,
+        filename: &str,
+        publish_time: DT,
+        dir: &Path,
+        site: &Site,
+        title_font: &ttf_parser::Face,
+    ) -> anyhow::Result<Post> {
+        self.history
+            .sort_by(|a, b| (b.time, &b.commit).cmp(&(a.time, &a.commit)));
+        let (_, lang) = file_name_to_id_ang_lang(filename)?;
+        let ctx = md::Context {
+            base: dir,
+            site,
+            lang,
+        };
+        let content =
+            md::render(self.content, &ctx).map_err(|e| anyhow::anyhow!("{}: {}", filename, e))?;
         serialize_single(
             filename,
-            self.content,
+            content,
+            publish_time,
             self.created,
             self.updated,
+            self.history,
             title_font,
         )
     }
@@ -204,12 +512,17 @@ impl Revlike {
         }
     }
 
-    fn time(&self, repo: &git2::Repository) -> Option<git2::Time> {
+    fn time(&self, repo: &git2::Repository, ignored: &HashSet<Oid>) -> Option<git2::Time> {
         let commit = match self {
             Revlike::WorkingDir => return None,
             Revlike::Commit(oid) => repo.find_commit(*oid).unwrap(),
         };
 
+        if ignored.contains(&commit.id()) {
+            log::debug!("Skipping due to {}", revision::IGNORE_REVS_FILE);
+            return None;
+        }
+
         let msg = commit.message();
         if msg.is_none() {
             log::warn!("Unparsable commit message at {}", commit.id());
@@ -243,14 +556,119 @@ impl ReaddirContext {
     }
 }
 
-fn revwalk_update_store(
+/// Whether a change to a post is large enough to count as an update, comparing the rendered
+/// text of both sides. Additions always are, and changes that cannot be rendered are assumed
+/// to be.
+fn significant(
+    repo: &git2::Repository,
+    delta: &git2::DiffDelta,
+    workdir: &Path,
+    ctx: &md::Context,
+) -> bool {
+    if delta.status() == git2::Delta::Added {
+        return true;
+    }
+    let text = |file: &git2::DiffFile| {
+        let content = revision::content(repo, file, workdir)?;
+        md::split(&content, ctx)
+            .and_then(|source| md::plain_text(source, ctx))
+            .ok()
+    };
+    let (Some(old), Some(new)) = (text(&delta.old_file()), text(&delta.new_file())) else {
+        return true;
+    };
+    let changed = revision::changed_chars(&old, &new);
+    let threshold = ctx.site.update_threshold.max(1);
+    if changed < threshold {
+        log::debug!(
+            "Ignoring change of {} characters to {}",
+            changed,
+            delta.new_file().path().unwrap().display()
+        );
+    }
+    changed >= threshold
+}
+
+/// Opens the repository containing `dir`, if it has the full history
+pub fn open_history(dir: &Path) -> anyhow::Result<git2::Repository> {
+    let repo = git2::Repository::discover(dir)?;
+    log::debug!("Found repository at {}", repo.path().display());
+    if repo.is_shallow() {
+        return Err(anyhow::anyhow!("shallow clone"));
+    }
+    repo.head()
+        .map_err(|e| anyhow::anyhow!("no commits: {}", e.message()))?;
+    Ok(repo)
+}
+
+/// Finds creation and update times of posts from the git history, or from the timestamp
+/// lockfile and file modification times if the history is unavailable
+fn update_store(
     dir: impl AsRef<Path>,
-    parsed: HashMap<String, ParsedMarkdown>,
+    parsed: HashMap<String, md::Source>,
+    site: &Site,
 ) -> anyhow::Result<HashMap<String, LatestFile>> {
-    let dir = &dir;
+    let dir = dir.as_ref();
+    let reason = match open_history(dir) {
+        Ok(repo) => return revwalk_update_store(&repo, dir, parsed, site),
+        Err(e) => e,
+    };
+    if site.write_timestamps {
+        return Err(anyhow::anyhow!(
+            "Cannot write timestamps without the git history: {}",
+            reason
+        ));
+    }
 
-    let repo = git2::Repository::discover(&dir)?;
-    log::debug!("Found repository at {}", repo.path().display());
+    let lockfile = match site.timestamps {
+        Some(ref path) => {
+            log::warn!(
+                "Git history unavailable ({}), reading timestamps from {}",
+                reason,
+                path.display()
+            );
+            timestamps::read(path).unwrap_or_else(|e| {
+                log::warn!("Unable to read {}: {}", path.display(), e);
+                Default::default()
+            })
+        }
+        None => {
+            log::warn!(
+                "Git history unavailable ({}), no timestamp lockfile",
+                reason
+            );
+            Default::default()
+        }
+    };
+
+    parsed
+        .into_iter()
+        .map(|(filename, content)| {
+            let mut file = LatestFile::new(content);
+            let times = match lockfile.get(&filename) {
+                Some(times) => *times,
+                None => {
+                    log::warn!("Using modification time as timestamps of {}", filename);
+                    let mtime = timestamps::mtime(&dir.join(&filename))?;
+                    timestamps::Timestamps {
+                        created: mtime,
+                        updated: mtime,
+                    }
+                }
+            };
+            file.created = Some(times.created);
+            file.updated = Some(times.updated);
+            Ok((filename, file))
+        })
+        .collect()
+}
+
+fn revwalk_update_store(
+    repo: &git2::Repository,
+    dir: &Path,
+    parsed: HashMap<String, md::Source>,
+    site: &Site,
+) -> anyhow::Result<HashMap<String, LatestFile>> {
     let mut revwalk = repo.revwalk()?;
     revwalk.set_sorting(Sort::TIME | Sort::TOPOLOGICAL | Sort::REVERSE)?;
     revwalk.reset()?;
@@ -265,8 +683,9 @@ fn revwalk_update_store(
     let mut repodir = std::fs::canonicalize(repo.path())?;
     repodir.pop();
 
-    let dir_abs = std::fs::canonicalize(dir.as_ref())?;
+    let dir_abs = std::fs::canonicalize(dir)?;
     let dir_rel = dir_abs.as_path().strip_prefix(&repodir)?;
+    let ignored = revision::ignored(repo, &repodir)?;
 
     let revs: impl Iterator<Item = anyhow::Result<Revlike>> =
         std::iter::once(anyhow::Result::Ok(Revlike::WorkingDir))
@@ -278,7 +697,7 @@ fn revwalk_update_store(
 
         let rename_state = ctx.rename.get(&rev).unwrap();
 
-        let mut diff = rev.diff(&repo)?;
+        let mut diff = rev.diff(repo)?;
         diff.find_similar(Some(
             DiffFindOptions::new()
                 .renames(true)
@@ -290,7 +709,7 @@ fn revwalk_update_store(
         ))?;
 
         /* Time Tracking */
-        let time_raw = rev.time(&repo);
+        let time_raw = rev.time(repo, &ignored);
         let time = if let Some(time_raw) = time_raw {
             let timezone = chrono::FixedOffset::east_opt(time_raw.offset_minutes() * 60).unwrap();
             Some(
@@ -330,6 +749,16 @@ fn revwalk_update_store(
             assert_ne!(status, git2::Delta::Deleted); // Deleted files should not be of interest
             if let Some(time) = time
                 && status != git2::Delta::Unmodified
+                && significant(
+                    repo,
+                    &delta,
+                    &repodir,
+                    &md::Context {
+                        base: dir,
+                        site,
+                        lang: file_name_to_id_ang_lang(latest_name).map_or("", |(_, lang)| lang),
+                    },
+                )
             {
                 let file = ctx.store.get_mut(latest_name).unwrap(); // Must exist
                 // Find latest update. The comparison is for the case of diverging history
@@ -340,6 +769,12 @@ fn revwalk_update_store(
                 if file.created.is_none() || file.created.as_ref().unwrap() > &time {
                     file.created = Some(time);
                 }
+
+                if let Revlike::Commit(oid) = rev {
+                    let commit = repo.find_commit(oid)?;
+                    file.history
+                        .push(revision::Revision::new(&commit, time, &delta));
+                }
             }
 
             let mut is_newfile = status == git2::Delta::Added;
@@ -362,7 +797,7 @@ fn revwalk_update_store(
             }
         }
 
-        for parent in rev.parents(&repo) {
+        for parent in rev.parents(repo) {
             if ctx.rename.contains_key(&parent) {
                 continue;
             }
@@ -375,84 +810,126 @@ fn revwalk_update_store(
 
 pub fn readdir<P: AsRef<Path>>(
     dir: P,
+    site: &Site,
     title_font: &ttf_parser::Face,
 ) -> anyhow::Result<HashMap<String, Post>> {
     let entries = std::fs::read_dir(&dir)?;
-    let mut parsed: HashMap<String, ParsedMarkdown> = HashMap::new();
+    let mut parsed: HashMap<String, md::Source> = HashMap::new();
 
     for entry in entries {
         let entry = entry?;
+        // Other files (e.g. included sources) may live alongside posts
+        if entry.path().extension().is_none_or(|ext| ext != "md") {
+            continue;
+        }
         let file = std::fs::read_to_string(entry.path())?;
-        log::info!("Parsing {}", entry.file_name().to_string_lossy());
-        parsed.insert(
-            String::from_utf8(entry.file_name().as_bytes().to_vec())?.to_string(),
-            md::parse(&file)?,
-        );
+        let filename = String::from_utf8(entry.file_name().as_bytes().to_vec())?;
+        log::info!("Parsing {}", filename);
+        let (_, lang) = file_name_to_id_ang_lang(&filename)?;
+        let ctx = md::Context {
+            base: dir.as_ref(),
+            site,
+            lang,
+        };
+        let parsed_file =
+            md::split(&file, &ctx).map_err(|e| anyhow::anyhow!("{}: {}", filename, e))?;
+        parsed.insert(filename, parsed_file);
     }
 
-    let timed = revwalk_update_store(&dir, parsed)?;
+    let dir = dir.as_ref();
+    let included = included(update_store(dir, parsed, site)?, site);
+
+    // Only after the full history walk, so that no post is lost
+    if site.write_timestamps
+        && let Some(ref path) = site.timestamps
+    {
+        log::info!("Writing timestamps to {}", path.display());
+        timestamps::write(
+            path,
+            included.iter().map(|(filename, file, _)| (filename, file)),
+        )?;
+    }
 
-    timed
+    included
         .into_par_iter()
-        .map(
-            |(filename, latest_file)| -> anyhow::Result<(String, Post)> {
-                let serialized = latest_file.serialize(&filename, title_font)?;
-                Ok((filename, serialized))
-            },
-        )
+        .map(|(filename, latest_file, publish_time)| {
+            let serialized =
+                latest_file.serialize(&filename, publish_time, dir, site, title_font)?;
+            Ok((filename, serialized))
+        })
+        .collect()
+}
+
+/// Posts part of the build, see [`Site::includes`], with their publish time
+fn included(store: HashMap<String, LatestFile>, site: &Site) -> Vec<(String, LatestFile, DT)> {
+    store
+        .into_iter()
+        .filter_map(|(filename, file)| {
+            let publish_time = file.publish_time(&filename);
+            let visibility = file.content.metadata.visibility;
+            site.includes(&filename, visibility, publish_time)
+                .then_some((filename, file, publish_time))
+        })
         .collect()
 }
 
 pub fn refresh_paths<P: AsRef<Path>, I: Iterator<Item = P>>(
     dir: impl AsRef<Path>,
     paths: I,
+    site: &Site,
     title_font: &ttf_parser::Face,
 ) -> anyhow::Result<HashMap<String, Option<Post>>> {
-    let mut parsed: HashMap<String, ParsedMarkdown> = HashMap::new();
+    let mut parsed: HashMap<String, md::Source> = HashMap::new();
     let mut skipped = Vec::new();
 
     for path in paths {
-        let file = match std::fs::read_to_string(&path)
-            .map_err(Into::into)
-            .and_then(|content| md::parse(&content))
-        {
+        let filename = path
+            .as_ref()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let file = match file_name_to_id_ang_lang(&filename).and_then(|(_, lang)| {
+            let ctx = md::Context {
+                base: dir.as_ref(),
+                site,
+                lang,
+            };
+            let content = std::fs::read_to_string(&path)?;
+            md::split(&content, &ctx)
+        }) {
             Ok(parsed) => parsed,
             Err(e) => {
                 log::info!("Unable to read file: {}", e);
-                let filename = path
-                    .as_ref()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_owned();
                 skipped.push(filename);
                 continue;
             }
         };
 
-        parsed.insert(
-            path.as_ref()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned(),
-            file,
-        );
+        parsed.insert(filename, file);
     }
 
-    let timed = revwalk_update_store(&dir, parsed)?;
+    let dir = dir.as_ref();
+    let timed = update_store(dir, parsed, site)?;
+    let mut excluded: Vec<_> = timed.keys().cloned().collect();
+    let included = included(timed, site);
+    excluded.retain(|filename| !included.iter().any(|(f, _, _)| f == filename));
 
-    let mut collected: HashMap<_, _> = timed
+    let mut collected: HashMap<_, _> = included
         .into_par_iter()
-        .map(|(filename, latest_file)| {
-            let serialized = latest_file.serialize(&filename, title_font)?;
-            Ok((filename, Some(serialized)))
+        .map(|(filename, latest_file, publish_time)| {
+            let serialized = latest_file
+                .serialize(&filename, publish_time, dir, site, title_font)
+                .inspect_err(|e| log::info!("Unable to render file: {}", e))
+                .ok();
+            (filename, serialized)
         })
-        .collect::<anyhow::Result<_>>()?;
+        .collect();
+    collected.extend(excluded.into_iter().map(|filename| (filename, None)));
 
     for s in skipped {
+        site.scheduled.lock().unwrap().remove(&s);
         collected.insert(s, None);
     }
     Ok(collected)
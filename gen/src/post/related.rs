@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::Post;
+
+/// Number of related posts kept for each post
+const LIMIT: usize = 5;
+/// Posts scoring below this are not considered related
+const THRESHOLD: f64 = 0.05;
+/// Weight of tag overlap, the rest being text similarity
+const TAG_WEIGHT: f64 = 0.4;
+
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
+pub struct Related {
+    pub id: String,
+    /// Between 0 and 1, rounded to 4 decimal places
+    pub score: f64,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // Kana
+        | '\u{3400}'..='\u{4dbf}' // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{2fa1f}' // Supplementary ideographs
+    )
+}
+
+fn flush(run: &mut Vec<char>, terms: &mut Vec<String>) {
+    match run.len() {
+        0 => {}
+        1 => terms.push(run[0].to_string()),
+        _ => terms.extend(run.windows(2).map(|w| w.iter().collect())),
+    }
+    run.clear();
+}
+
+/// Splits text into terms. Latin words are lowercased, and runs of CJK characters,
+/// which are not delimited by spaces, are split into overlapping bigrams.
+fn tokenize(text: &str, terms: &mut Vec<String>) {
+    let mut run = Vec::new();
+    let mut run_end = 0;
+    for (start, word) in text.unicode_word_indices() {
+        if word.chars().all(is_cjk) {
+            if start != run_end {
+                flush(&mut run, terms);
+            }
+            run.extend(word.chars());
+            run_end = start + word.len();
+        } else {
+            flush(&mut run, terms);
+            if word.chars().count() > 1 {
+                terms.push(word.to_lowercase());
+            }
+        }
+    }
+    flush(&mut run, terms);
+}
+
+/// Unit-length TF-IDF vector, sorted by term
+type Vector = Vec<(usize, f64)>;
+
+fn dot(a: &Vector, b: &Vector) -> f64 {
+    let (mut i, mut j, mut sum) = (0, 0, 0.);
+    while i < a.len() && j < b.len() {
+        match a[i].0.cmp(&b[j].0) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                sum += a[i].1 * b[j].1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    sum
+}
+
+fn jaccard(a: &HashSet<&str>, b: &HashSet<&str>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.;
+    }
+    a.intersection(b).count() as f64 / a.union(b).count() as f64
+}
+
+/// Computes TF-IDF vectors of `posts`, all in the same language
+fn vectors(posts: &[&Post]) -> Vec<Vector> {
+    let mut vocab: BTreeMap<String, usize> = BTreeMap::new();
+    let counts: Vec<BTreeMap<usize, usize>> = posts
+        .iter()
+        .map(|p| {
+            let mut terms = Vec::new();
+            tokenize(&p.metadata.title, &mut terms);
+            tokenize(&p.plain, &mut terms);
+            let mut counts = BTreeMap::new();
+            for t in terms {
+                let next = vocab.len();
+                let id = *vocab.entry(t).or_insert(next);
+                *counts.entry(id).or_default() += 1;
+            }
+            counts
+        })
+        .collect();
+
+    let mut df: HashMap<usize, usize> = HashMap::new();
+    for c in &counts {
+        for id in c.keys() {
+            *df.entry(*id).or_default() += 1;
+        }
+    }
+
+    let n = posts.len() as f64;
+    counts
+        .into_iter()
+        .map(|c| {
+            let mut v: Vector = c
+                .into_iter()
+                .map(|(id, count)| {
+                    let idf = (n / df[&id] as f64).ln();
+                    (id, (1. + count as f64).ln() * idf)
+                })
+                .filter(|(_, w)| *w > 0.)
+                .collect();
+            let norm = v.iter().map(|(_, w)| w * w).sum::<f64>().sqrt();
+            if norm > 0. {
+                for (_, w) in &mut v {
+                    *w /= norm;
+                }
+            }
+            v
+        })
+        .collect()
+}
+
+/// Fills in related posts of all posts, combining tag overlap and TF-IDF similarity of
+/// the plaintext. Only listed posts in the same language are suggested.
+///
+/// Ties are broken by post ID, so the result does not depend on the order of `posts`.
+pub fn link(posts: &mut HashMap<String, Post>) {
+    let mut by_lang: BTreeMap<&str, Vec<&Post>> = BTreeMap::new();
+    for post in posts.values() {
+        by_lang.entry(&post.metadata.lang).or_default().push(post);
+    }
+
+    let mut related: HashMap<(String, String), Vec<Related>> = HashMap::new();
+    for (lang, mut group) in by_lang {
+        group.sort_by(|a, b| a.metadata.id.cmp(&b.metadata.id));
+        let vectors = vectors(&group);
+        let tags: Vec<HashSet<&str>> = group
+            .iter()
+            .map(|p| p.metadata.tags.iter().map(String::as_str).collect())
+            .collect();
+
+        for (i, post) in group.iter().enumerate() {
+            let mut scored: Vec<Related> = group
+                .iter()
+                .enumerate()
+                .filter(|(j, p)| *j != i && p.metadata.visibility.is_listed())
+                .map(|(j, p)| {
+                    let score = TAG_WEIGHT * jaccard(&tags[i], &tags[j])
+                        + (1. - TAG_WEIGHT) * dot(&vectors[i], &vectors[j]);
+                    Related {
+                        id: p.metadata.id.clone(),
+                        score: (score * 1e4).round() / 1e4,
+                    }
+                })
+                .filter(|r| r.score >= THRESHOLD)
+                .collect();
+            scored.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+            scored.truncate(LIMIT);
+            related.insert((post.metadata.id.clone(), lang.to_owned()), scored);
+        }
+    }
+
+    for post in posts.values_mut() {
+        let key = (post.metadata.id.clone(), post.metadata.lang.clone());
+        post.metadata.related = related.remove(&key).unwrap_or_default();
+    }
+}
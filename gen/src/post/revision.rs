@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use git2::{DiffFile, Oid, Repository};
+use pulldown_cmark_escape::escape_html;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+use super::{DT, md};
+
+/// Revisions listed in this file at the root of the repository do not count as updates,
+/// similar to git's `blame.ignoreRevsFile`
+pub const IGNORE_REVS_FILE: &str = ".layered-ignore-revs";
+
+/// Unchanged lines shown around changes in diffs
+const DIFF_CONTEXT: usize = 2;
+
+/// Reads the ignored revisions, one commit per line. Empty lines and `#` comments are
+/// skipped, and abbreviated hashes are resolved.
+pub fn ignored(repo: &Repository, workdir: &Path) -> anyhow::Result<HashSet<Oid>> {
+    let path = workdir.join(IGNORE_REVS_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut revs = HashSet::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match repo.revparse_single(line).and_then(|o| o.peel_to_commit()) {
+            Ok(commit) => {
+                revs.insert(commit.id());
+            }
+            Err(e) => log::warn!("Unknown revision {} in {}: {}", line, IGNORE_REVS_FILE, e),
+        }
+    }
+    Ok(revs)
+}
+
+/// Content of one side of a diff, read from the working directory if not in the repository
+pub fn content(repo: &Repository, file: &DiffFile, workdir: &Path) -> Option<String> {
+    let blob = (!file.id().is_zero())
+        .then(|| repo.find_blob(file.id()).ok())
+        .flatten();
+    match blob {
+        Some(blob) => String::from_utf8(blob.content().to_vec()).ok(),
+        None => std::fs::read_to_string(workdir.join(file.path()?)).ok(),
+    }
+}
+
+/// Number of characters inserted or deleted between two rendered texts, ignoring whitespace
+pub fn changed_chars(old: &str, new: &str) -> usize {
+    let text = |s: &str| -> String { s.chars().filter(|c| !c.is_whitespace()).collect() };
+    let (old, new) = (text(old), text(new));
+    TextDiff::from_chars(&old, &new)
+        .iter_all_changes()
+        .filter(|c| c.tag() != ChangeTag::Equal)
+        .count()
+}
+
+/// Renders the changed lines of the text of a post as HTML, with some context. Each hunk
+/// is a `<pre class="diff">`, with removed lines in `<del>` and added lines in `<ins>`.
+pub fn diff_html(old: &str, new: &str) -> String {
+    let (old, new) = (md::source_text(old), md::source_text(new));
+    let diff = TextDiff::from_lines(&old, &new);
+    let mut html = String::new();
+    for (i, ops) in diff.grouped_ops(DIFF_CONTEXT).iter().enumerate() {
+        if i > 0 {
+            html += "<hr>\n";
+        }
+        html += "<pre class=\"diff\">";
+        for change in ops.iter().flat_map(|op| diff.iter_changes(op)) {
+            let (open, close) = match change.tag() {
+                ChangeTag::Delete => ("<del>", "</del>"),
+                ChangeTag::Insert => ("<ins>", "</ins>"),
+                ChangeTag::Equal => ("", ""),
+            };
+            html += open;
+            escape_html(&mut html, change.value().trim_end_matches('\n')).unwrap();
+            html += close;
+            html += "\n";
+        }
+        html += "</pre>\n";
+    }
+    html
+}
+
+/// A commit counting as an update of a post
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
+pub struct Revision {
+    pub commit: String,
+    /// Author date
+    #[ts(type = "string")]
+    pub time: DT,
+    /// Commit message, without trailing whitespace
+    pub message: String,
+    /// Path of the post at this revision, relative to the repository root
+    pub path: String,
+    /// Whether the post was added in this revision, rather than edited
+    #[serde(skip)]
+    #[ts(skip)]
+    pub created: bool,
+    /// Blobs of the post before and after this revision
+    #[serde(skip)]
+    #[ts(skip)]
+    blobs: (Oid, Oid),
+}
+
+impl Revision {
+    pub fn new(commit: &git2::Commit, time: DT, delta: &git2::DiffDelta) -> Self {
+        let path = delta.new_file().path().unwrap();
+        Revision {
+            commit: commit.id().to_string(),
+            time,
+            message: commit.message().unwrap_or_default().trim_end().to_owned(),
+            path: path.to_string_lossy().into_owned(),
+            created: delta.status() == git2::Delta::Added,
+            blobs: (delta.old_file().id(), delta.new_file().id()),
+        }
+    }
+
+    /// Changes to the text of the post, see [`diff_html`]. Empty for creations and for
+    /// revisions not changing the text, e.g. renames.
+    pub fn diff(&self, repo: &Repository) -> anyhow::Result<String> {
+        let (old, new) = self.blobs;
+        if self.created || old == new {
+            return Ok(String::new());
+        }
+        let text = |id| -> anyhow::Result<String> {
+            Ok(String::from_utf8(repo.find_blob(id)?.content().to_vec())?)
+        };
+        Ok(diff_html(&text(old)?, &text(new)?))
+    }
+}
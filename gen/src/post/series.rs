@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use super::Post;
+
+/// Series a post belongs to, as given in the frontmatter
+#[derive(Debug, Clone)]
+pub struct SeriesRef {
+    pub id: String,
+    /// Explicit position within the series
+    pub part: Option<u32>,
+}
+
+/// Parses `series: id` or `series: id, part`
+pub fn parse_ref(value: &str) -> anyhow::Result<SeriesRef> {
+    let (id, part) = match value.split_once(',') {
+        Some((id, part)) => (id.trim(), Some(part.trim().parse()?)),
+        None => (value.trim(), None),
+    };
+    if id.is_empty() {
+        return Err(anyhow::anyhow!("Empty series id"));
+    }
+    Ok(SeriesRef {
+        id: id.to_owned(),
+        part,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
+pub struct SeriesPart {
+    pub id: String,
+    pub title: String,
+    /// Part number given by the author. Parts without one continue from the preceding part.
+    pub part: u32,
+}
+
+/// Navigation within the series a post belongs to
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
+pub struct SeriesNav {
+    pub id: String,
+    pub title: String,
+    /// Part number of this post, see [`SeriesPart::part`]
+    pub part: u32,
+    pub parts: Vec<SeriesPart>,
+    /// ID of the previous part
+    pub prev: Option<String>,
+    /// ID of the next part
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Serialize, ts_rs::TS)]
+#[ts(export)]
+pub struct Series {
+    pub id: String,
+    pub lang: String,
+    pub title: String,
+    pub parts: Vec<SeriesPart>,
+}
+
+/// Series titles, by series ID and language
+pub type Titles = HashMap<String, HashMap<String, String>>;
+
+/// Collects series from listed posts, sorted by ID and language.
+///
+/// Parts with an explicit part number come first in that order, and the rest follow
+/// by publish time.
+pub fn collect<'a>(titles: &Titles, posts: impl Iterator<Item = &'a Post>) -> Vec<Series> {
+    let mut grouped: BTreeMap<(&str, &str), Vec<(&SeriesRef, &Post)>> = BTreeMap::new();
+    for post in posts.filter(|p| p.metadata.visibility.is_listed()) {
+        if let Some(ref r) = post.series {
+            grouped
+                .entry((&r.id, &post.metadata.lang))
+                .or_default()
+                .push((r, post));
+        }
+    }
+
+    grouped
+        .into_iter()
+        .map(|((id, lang), mut parts)| {
+            parts.sort_by(|(ra, a), (rb, b)| {
+                (
+                    ra.part.is_none(),
+                    ra.part,
+                    a.metadata.publish_time,
+                    &a.metadata.id,
+                )
+                    .cmp(&(
+                        rb.part.is_none(),
+                        rb.part,
+                        b.metadata.publish_time,
+                        &b.metadata.id,
+                    ))
+            });
+            for w in parts.windows(2) {
+                if w[0].0.part.is_some() && w[0].0.part == w[1].0.part {
+                    log::warn!(
+                        "Posts {} and {} are both part {} of series {}",
+                        w[0].1.metadata.id,
+                        w[1].1.metadata.id,
+                        w[0].0.part.unwrap(),
+                        id
+                    );
+                }
+            }
+
+            let title = titles
+                .get(id)
+                .and_then(|t| t.get(lang))
+                .cloned()
+                .unwrap_or_else(|| id.to_owned());
+            let mut number = 0;
+            Series {
+                id: id.to_owned(),
+                lang: lang.to_owned(),
+                title,
+                parts: parts
+                    .iter()
+                    .map(|(r, p)| {
+                        number = r.part.unwrap_or(number + 1);
+                        SeriesPart {
+                            id: p.metadata.id.clone(),
+                            title: p.metadata.title.clone(),
+                            part: number,
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Fills in series navigation of all posts. Unlisted posts are left out of series.
+pub fn link(titles: &Titles, posts: &mut HashMap<String, Post>) {
+    let series = collect(titles, posts.values());
+    let mut by_id: HashMap<(String, String), &mut Post> = posts
+        .values_mut()
+        .map(|p| {
+            p.metadata.series = None;
+            ((p.metadata.id.clone(), p.metadata.lang.clone()), p)
+        })
+        .collect();
+
+    for s in series {
+        // Neighbors are by position, as part numbers may have gaps
+        for (i, part) in s.parts.iter().enumerate() {
+            let Some(post) = by_id.get_mut(&(part.id.clone(), s.lang.clone())) else {
+                log::warn!("Post {} of series {} is not built", part.id, s.id);
+                continue;
+            };
+            post.metadata.series = Some(SeriesNav {
+                id: s.id.clone(),
+                title: s.title.clone(),
+                part: part.part,
+                parts: s.parts.clone(),
+                prev: i.checked_sub(1).map(|j| s.parts[j].id.clone()),
+                next: s.parts.get(i + 1).map(|p| p.id.clone()),
+            });
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Tag, TagEnd};
+
+/// Markers around spoiler content. The plaintext generator records spoiler ranges here.
+/// End markers are tagged with a comment, as other transforms also close spans and divs.
+pub const INLINE_START: &str = "<span class=\"spoiler\" tabindex=\"0\">";
+pub const INLINE_END: &str = "</span><!--/spoiler-->";
+pub const BLOCK_START: &str = "<div class=\"spoiler-content\">\n";
+pub const BLOCK_END: &str = "</div><!--/spoiler-->\n";
+
+const DELIMITER: &str = "||";
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+fn is_inline(tag: &Tag) -> bool {
+    matches!(
+        tag,
+        Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link { .. } | Tag::Image { .. }
+    )
+}
+
+fn is_inline_end(tag: &TagEnd) -> bool {
+    matches!(
+        tag,
+        TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link | TagEnd::Image
+    )
+}
+
+/// Pairs up delimiters within a run of inline content. An unpaired trailing delimiter
+/// is kept as-is, so e.g. a lone `a || b` is not affected.
+fn flush<'a>(segment: &mut Vec<Spanned<'a>>, output: &mut Vec<Spanned<'a>>) {
+    let mut image_depth = 0;
+    let mut count = 0;
+    for (ev, _) in segment.iter() {
+        match ev {
+            Event::Start(Tag::Image { .. }) => image_depth += 1,
+            Event::End(TagEnd::Image) => image_depth -= 1,
+            Event::Text(s) if image_depth == 0 => count += s.matches(DELIMITER).count(),
+            _ => {}
+        }
+    }
+
+    let mut remaining = count - count % 2;
+    let mut open = false;
+    for (ev, range) in segment.drain(..) {
+        match ev {
+            Event::Start(Tag::Image { .. }) => image_depth += 1,
+            Event::End(TagEnd::Image) => image_depth -= 1,
+            Event::Text(ref s) if image_depth == 0 && remaining > 0 && s.contains(DELIMITER) => {
+                let mut pieces = s.split(DELIMITER).peekable();
+                while let Some(piece) = pieces.next() {
+                    let mut text = piece.to_owned();
+                    if pieces.peek().is_some() && remaining == 0 {
+                        text.push_str(DELIMITER);
+                    }
+                    if !text.is_empty() {
+                        output.push((Event::Text(text.into()), range.clone()));
+                    }
+                    if pieces.peek().is_some() && remaining > 0 {
+                        let marker = if open { INLINE_END } else { INLINE_START };
+                        output.push((Event::InlineHtml(marker.into()), range.clone()));
+                        open = !open;
+                        remaining -= 1;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        output.push((ev, range));
+    }
+}
+
+/// Renders inline `||spoiler||` text as spans hidden until clicked.
+///
+/// Delimiters are paired within each paragraph (or heading, table cell, etc.), and
+/// are not recognized within code or image alt texts.
+pub fn transform<'a>(events: Vec<Spanned<'a>>) -> Vec<Spanned<'a>> {
+    let mut output = Vec::with_capacity(events.len());
+    let mut segment = Vec::new();
+    let mut in_codeblock = false;
+
+    for (event, range) in events {
+        let inline = match &event {
+            Event::Start(tag) => is_inline(tag),
+            Event::End(tag) => is_inline_end(tag),
+            Event::Html(_) | Event::Rule => false,
+            _ => !in_codeblock,
+        };
+        if inline {
+            segment.push((event, range));
+            continue;
+        }
+
+        flush(&mut segment, &mut output);
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_codeblock = true,
+            Event::End(TagEnd::CodeBlock) => in_codeblock = false,
+            _ => {}
+        }
+        output.push((event, range));
+    }
+    flush(&mut segment, &mut output);
+
+    output
+}
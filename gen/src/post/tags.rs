@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::collate::{self, Keys};
+use super::{DT, Post};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TagDef {
+    /// Localized names, by language
+    #[serde(default)]
+    name: BTreeMap<String, String>,
+    /// Localized descriptions, by language
+    #[serde(default)]
+    desc: BTreeMap<String, String>,
+    parent: Option<String>,
+    /// Other spellings normalized to this tag
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+/// Known tags, keyed by slug
+#[derive(Default)]
+pub struct Registry {
+    tags: BTreeMap<String, TagDef>,
+    /// Lowercased slugs and aliases to slugs
+    lookup: HashMap<String, String>,
+}
+
+impl Registry {
+    /// Parses a YAML registry, mapping slugs to their definitions
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let tags: BTreeMap<String, TagDef> = serde_yaml::from_str(source)?;
+
+        let mut lookup = HashMap::new();
+        for (slug, def) in &tags {
+            for key in std::iter::once(slug).chain(&def.aliases) {
+                if let Some(prev) = lookup.insert(key.to_lowercase(), slug.clone())
+                    && prev != *slug
+                {
+                    return Err(anyhow::anyhow!(
+                        "Tag {} is ambiguous between {} and {}",
+                        key,
+                        prev,
+                        slug
+                    ));
+                }
+            }
+
+            // Walk up the hierarchy to check for unknown parents and cycles
+            let mut seen = HashSet::from([slug.as_str()]);
+            let mut cur = def;
+            while let Some(ref parent) = cur.parent {
+                cur = tags
+                    .get(parent)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown parent {} of tag {}", parent, slug))?;
+                if !seen.insert(parent) {
+                    return Err(anyhow::anyhow!("Cyclic parent of tag {}", slug));
+                }
+            }
+        }
+
+        Ok(Registry { tags, lookup })
+    }
+
+    pub fn slugs(&self) -> impl Iterator<Item = &str> {
+        self.tags.keys().map(String::as_str)
+    }
+
+    /// Languages with localized tag names
+    pub fn langs(&self) -> BTreeSet<&str> {
+        self.tags
+            .values()
+            .flat_map(|def| def.name.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// Localized name of a tag, falling back to its slug
+    pub fn name<'a>(&'a self, slug: &'a str, lang: &str) -> &'a str {
+        self.tags
+            .get(slug)
+            .and_then(|def| def.name.get(lang))
+            .map_or(slug, String::as_str)
+    }
+
+    /// Maps tags to their slugs, dropping duplicates. Unknown tags are kept as-is, with
+    /// a warning unless the registry is empty.
+    pub fn normalize(&self, tags: Vec<String>) -> Vec<String> {
+        let mut normalized = Vec::with_capacity(tags.len());
+        for tag in tags {
+            let tag = match self.lookup.get(&tag.to_lowercase()) {
+                Some(slug) => slug.clone(),
+                None => {
+                    if !self.tags.is_empty() {
+                        log::warn!("Unknown tag: {}", tag);
+                    }
+                    tag
+                }
+            };
+            if !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+        normalized
+    }
+}
+
+#[derive(Serialize, ts_rs::TS)]
+#[ts(export)]
+pub struct TagInfo {
+    pub slug: String,
+    /// Localized names, by language. Empty for tags missing from the registry.
+    pub name: BTreeMap<String, String>,
+    pub desc: BTreeMap<String, String>,
+    pub parent: Option<String>,
+    pub children: Vec<String>,
+    /// Collation keys of the name, by language
+    pub sort_key: BTreeMap<String, String>,
+    /// Number of listed posts with this tag, counting translations once
+    pub count: usize,
+    #[ts(as = "Option<String>")]
+    pub first_post: Option<DT>,
+    #[ts(as = "Option<String>")]
+    pub last_post: Option<DT>,
+}
+
+/// Collects all registered tags and those used by listed posts, sorted by slug
+pub fn collect<'a>(
+    registry: &Registry,
+    keys: &Keys,
+    posts: impl Iterator<Item = &'a Post>,
+) -> Vec<TagInfo> {
+    let mut infos: BTreeMap<&str, TagInfo> = registry
+        .tags
+        .iter()
+        .map(|(slug, def)| {
+            let info = TagInfo {
+                slug: slug.clone(),
+                name: def.name.clone(),
+                desc: def.desc.clone(),
+                parent: def.parent.clone(),
+                children: Vec::new(),
+                sort_key: BTreeMap::new(),
+                count: 0,
+                first_post: None,
+                last_post: None,
+            };
+            (slug.as_str(), info)
+        })
+        .collect();
+    for (slug, def) in &registry.tags {
+        if let Some(ref parent) = def.parent {
+            infos
+                .get_mut(parent.as_str())
+                .unwrap()
+                .children
+                .push(slug.clone());
+        }
+    }
+
+    let mut counted: HashSet<(&str, &str)> = HashSet::new();
+    for post in posts.filter(|p| p.metadata.visibility.is_listed()) {
+        for tag in &post.metadata.tags {
+            let info = infos.entry(tag).or_insert_with(|| TagInfo {
+                slug: tag.clone(),
+                name: BTreeMap::new(),
+                desc: BTreeMap::new(),
+                parent: None,
+                children: Vec::new(),
+                sort_key: BTreeMap::new(),
+                count: 0,
+                first_post: None,
+                last_post: None,
+            });
+            if counted.insert((tag, &post.metadata.id)) {
+                info.count += 1;
+            }
+            let time = post.metadata.publish_time;
+            if info.first_post.is_none_or(|t| t > time) {
+                info.first_post = Some(time);
+            }
+            if info.last_post.is_none_or(|t| t < time) {
+                info.last_post = Some(time);
+            }
+        }
+    }
+
+    for info in infos.values_mut() {
+        for lang in keys.keys() {
+            let text = collate::sort_text(registry.name(&info.slug, lang), lang);
+            info.sort_key
+                .insert(lang.clone(), collate::key(keys, lang, &text));
+        }
+    }
+
+    infos.into_values().collect()
+}
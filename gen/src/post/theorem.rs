@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{Event, Tag, TagEnd};
+use regex::Regex;
+
+use super::callout;
+use super::xref::{self, Kind, Xrefs};
+
+static MARKER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\[!(\w+)\][ \t]*").unwrap());
+static LABEL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s*\{#([\w:-]+)\}\s*$").unwrap());
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+/// Environments, numbered ones sharing counters with their cross-reference kinds
+#[derive(Clone, Copy)]
+enum Env {
+    Numbered(Kind),
+    Proof,
+}
+
+impl Env {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "theorem" => Some(Env::Numbered(Kind::Theorem)),
+            "lemma" => Some(Env::Numbered(Kind::Lemma)),
+            "definition" => Some(Env::Numbered(Kind::Definition)),
+            "proof" => Some(Env::Proof),
+            _ => None,
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            Env::Numbered(Kind::Theorem) => "theorem",
+            Env::Numbered(Kind::Lemma) => "lemma",
+            Env::Numbered(Kind::Definition) => "definition",
+            Env::Numbered(_) => unreachable!(),
+            Env::Proof => "proof",
+        }
+    }
+}
+
+fn proof_label(lang: &str) -> &'static str {
+    match lang {
+        "zh-CN" => "证明",
+        _ => "Proof",
+    }
+}
+
+/// Strips a `[!KIND]` marker and the rest of its line from the start of a blockquote, see
+/// [`callout::take_marker`], returning the environment, the name and the label given by a trailing `{#thm:label}`.
+fn take_marker<'a>(
+    input: &mut VecDeque<Spanned<'a>>,
+) -> Option<(Env, Vec<Spanned<'a>>, Option<String>)> {
+    let (env, mut name) = callout::take_marker(input, &MARKER_RE, |cap| {
+        Env::parse(cap.get(1).unwrap().as_str())
+    })?;
+
+    let mut label = None;
+    if let Some((Event::Text(s), _)) = name.last_mut()
+        && let Some(cap) = LABEL_RE.captures(s)
+    {
+        label = Some(cap.get(1).unwrap().as_str().to_owned());
+        let before = s[..cap.get(0).unwrap().start()].to_owned();
+        *s = before.into();
+    }
+    name.retain(|(ev, _)| !matches!(ev, Event::Text(s) if s.is_empty()));
+    Some((env, name, label))
+}
+
+/// Ends a proof with a QED marker, placed at the end of the last paragraph if possible
+fn qed(output: &mut Vec<Spanned>, range: &Range<usize>) {
+    if matches!(output.last(), Some((Event::End(TagEnd::Paragraph), _))) {
+        let end = output.pop().unwrap();
+        output.extend(
+            [
+                Event::InlineHtml("<span class=\"qed\">".into()),
+                Event::Text("∎".into()),
+                Event::InlineHtml("</span>".into()),
+            ]
+            .map(|ev| (ev, range.clone())),
+        );
+        output.push(end);
+    } else {
+        output.push((
+            Event::Html("<p class=\"qed\">∎</p>\n".into()),
+            range.clone(),
+        ));
+    }
+}
+
+/// Renders blockquotes starting with `[!THEOREM]`, `[!LEMMA]`, `[!DEFINITION]` or
+/// `[!PROOF]` as theorem-like environments.
+///
+/// The rest of the marker line is the name of the environment, e.g. `[!THEOREM] Fermat`.
+/// Theorems, lemmas and definitions are numbered, and may be labeled with a trailing
+/// `{#thm:label}`, `{#lem:label}` or `{#def:label}` for cross-references. Proofs end
+/// with a QED marker, and their name replaces the localized "Proof" title.
+pub fn transform<'a>(
+    events: Vec<Spanned<'a>>,
+    xrefs: &mut Xrefs,
+    lang: &str,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<Spanned<'a>> {
+    let mut input: VecDeque<_> = events.into();
+    let mut output = Vec::with_capacity(input.len());
+    // Enclosing blockquotes, None if not an environment
+    let mut stack: Vec<Option<Env>> = Vec::new();
+
+    while let Some((event, range)) = input.pop_front() {
+        match event {
+            Event::Start(Tag::BlockQuote(None)) => {
+                let Some((env, name, label)) = take_marker(&mut input) else {
+                    stack.push(None);
+                    output.push((event, range));
+                    continue;
+                };
+
+                let mut title = Vec::new();
+                let anchor = match env {
+                    Env::Numbered(kind) => match xrefs.number(kind, label.as_deref()) {
+                        Ok(num) => {
+                            let number = format!("{} {}", kind.name(lang), num);
+                            title.push(Event::InlineHtml("<span class=\"theorem-number\">".into()));
+                            title.push(Event::Text(number.into()));
+                            title.push(Event::InlineHtml("</span>".into()));
+                            label.as_deref().map(xref::anchor)
+                        }
+                        Err(e) => {
+                            report(range.start, e);
+                            None
+                        }
+                    },
+                    Env::Proof => {
+                        if let Some(label) = label {
+                            report(
+                                range.start,
+                                anyhow::anyhow!("Proofs cannot be labeled: {}", label),
+                            );
+                        }
+                        if name.is_empty() {
+                            title.push(Event::Text(proof_label(lang).into()));
+                        }
+                        None
+                    }
+                };
+
+                let open = match anchor {
+                    Some(anchor) => format!(
+                        "<section class=\"theorem theorem-{}\" id=\"{}\">\n",
+                        env.class(),
+                        anchor
+                    ),
+                    None => format!("<section class=\"theorem theorem-{}\">\n", env.class()),
+                };
+                output.push((Event::Html(open.into()), range.clone()));
+                output.push((
+                    Event::Html("<p class=\"theorem-title\">".into()),
+                    range.clone(),
+                ));
+                output.extend(title.into_iter().map(|ev| (ev, range.clone())));
+                if !name.is_empty() {
+                    if let Env::Numbered(_) = env {
+                        output.push((Event::Text(" (".into()), range.clone()));
+                        output.extend(name);
+                        output.push((Event::Text(")".into()), range.clone()));
+                    } else {
+                        output.extend(name);
+                    }
+                }
+                output.push((Event::Html(callout::TITLE_ENDS[0].into()), range));
+                stack.push(Some(env));
+            }
+            Event::Start(Tag::BlockQuote(_)) => {
+                stack.push(None);
+                output.push((event, range));
+            }
+            Event::End(TagEnd::BlockQuote(_)) => match stack.pop().flatten() {
+                Some(env) => {
+                    if let Env::Proof = env {
+                        qed(&mut output, &range);
+                    }
+                    output.push((Event::Html("</section>\n".into()), range));
+                }
+                None => output.push((event, range)),
+            },
+            event => output.push((event, range)),
+        }
+    }
+
+    output
+}
@@ -0,0 +1,52 @@
+//! Timestamp lockfile, recording when posts were created and last updated according to
+//! the git history. Builds without the full history (e.g. from a tarball or a shallow
+//! clone) read times from it instead, falling back to file modification times.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{DT, LatestFile};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Timestamps {
+    pub created: DT,
+    pub updated: DT,
+}
+
+/// Timestamps by filename
+pub type Lockfile = BTreeMap<String, Timestamps>;
+
+pub fn read(path: &Path) -> anyhow::Result<Lockfile> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Writes the timestamps of all committed posts, sorted by filename
+pub fn write<'a>(
+    path: &Path,
+    store: impl Iterator<Item = (&'a String, &'a LatestFile)>,
+) -> anyhow::Result<()> {
+    let lockfile: Lockfile = store
+        .filter_map(|(filename, file)| {
+            let times = Timestamps {
+                created: file.created?,
+                updated: file.updated?,
+            };
+            Some((filename.clone(), times))
+        })
+        .collect();
+    let mut content = serde_json::to_string_pretty(&lockfile)?;
+    content.push('\n');
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+/// Modification time of a file, clamped to the build time so that it respects
+/// `SOURCE_DATE_EPOCH`
+pub fn mtime(path: &Path) -> anyhow::Result<DT> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    let modified = chrono::DateTime::<chrono::Local>::from(modified).fixed_offset();
+    Ok(modified.min(super::now()))
+}
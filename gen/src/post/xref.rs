@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{Event, Tag, TagEnd};
+use regex::Regex;
+
+static REF_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"@((fig|tbl|lst|thm|lem|def):[\w-]+)").unwrap());
+
+/// Kinds of numbered targets, each with its own counter within a post
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    Figure,
+    Table,
+    Listing,
+    Theorem,
+    Lemma,
+    Definition,
+}
+
+impl Kind {
+    /// Label prefix, e.g. `fig` in `fig:overview`
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Kind::Figure => "fig",
+            Kind::Table => "tbl",
+            Kind::Listing => "lst",
+            Kind::Theorem => "thm",
+            Kind::Lemma => "lem",
+            Kind::Definition => "def",
+        }
+    }
+
+    pub fn name(self, lang: &str) -> &'static str {
+        match (self, lang) {
+            (Kind::Figure, "zh-CN") => "图",
+            (Kind::Table, "zh-CN") => "表",
+            (Kind::Listing, "zh-CN") => "代码",
+            (Kind::Theorem, "zh-CN") => "定理",
+            (Kind::Lemma, "zh-CN") => "引理",
+            (Kind::Definition, "zh-CN") => "定义",
+            (Kind::Figure, _) => "Figure",
+            (Kind::Table, _) => "Table",
+            (Kind::Listing, _) => "Listing",
+            (Kind::Theorem, _) => "Theorem",
+            (Kind::Lemma, _) => "Lemma",
+            (Kind::Definition, _) => "Definition",
+        }
+    }
+}
+
+/// Separator between the number and the caption text
+pub fn caption_separator(lang: &str) -> &'static str {
+    match lang {
+        "zh-CN" => "：",
+        _ => ": ",
+    }
+}
+
+pub fn anchor(label: &str) -> String {
+    label.replace(':', "-")
+}
+
+/// Numbered targets within a single post
+#[derive(Default)]
+pub struct Xrefs {
+    counters: HashMap<Kind, usize>,
+    targets: HashMap<String, (Kind, usize)>,
+}
+
+impl Xrefs {
+    /// Assigns the next number of `kind`, registering `label` if given.
+    pub fn number(&mut self, kind: Kind, label: Option<&str>) -> anyhow::Result<usize> {
+        let counter = self.counters.entry(kind).or_default();
+        *counter += 1;
+        let num = *counter;
+
+        if let Some(label) = label {
+            if label.split_once(':').map(|(p, _)| p) != Some(kind.prefix()) {
+                return Err(anyhow::anyhow!(
+                    "Label {} should start with {}:",
+                    label,
+                    kind.prefix()
+                ));
+            }
+            if self.targets.insert(label.to_owned(), (kind, num)).is_some() {
+                return Err(anyhow::anyhow!("Duplicated label {}", label));
+            }
+        }
+        Ok(num)
+    }
+
+    /// Text of a reference to `label`, e.g. "Figure 2"
+    pub fn text(&self, label: &str, lang: &str) -> Option<String> {
+        let (kind, num) = self.targets.get(label)?;
+        Some(format!("{} {}", kind.name(lang), num))
+    }
+}
+
+/// Replace `@fig:label`, `@thm:label` etc. in text with links to the targets.
+///
+/// References within code, links and image alt texts are left untouched. The reference
+/// text is kept as a text event so it also appears in the plaintext.
+pub fn resolve<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    xrefs: &Xrefs,
+    lang: &str,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut output = Vec::with_capacity(events.len());
+    let mut literal_depth = 0;
+
+    for (event, range) in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_) | Tag::Link { .. } | Tag::Image { .. }) => {
+                literal_depth += 1
+            }
+            Event::End(TagEnd::CodeBlock | TagEnd::Link | TagEnd::Image) => literal_depth -= 1,
+            Event::Text(ref s) if literal_depth == 0 && REF_RE.is_match(s) => {
+                let mut last = 0;
+                for cap in REF_RE.captures_iter(s) {
+                    let whole = cap.get(0).unwrap();
+                    // Skip e.g. email addresses
+                    if s[..whole.start()]
+                        .chars()
+                        .next_back()
+                        .is_some_and(char::is_alphanumeric)
+                    {
+                        continue;
+                    }
+                    let label = cap.get(1).unwrap().as_str();
+                    let Some(text) = xrefs.text(label, lang) else {
+                        report(
+                            range.start,
+                            anyhow::anyhow!("Reference to undefined label {}", label),
+                        );
+                        continue;
+                    };
+
+                    if last < whole.start() {
+                        let before = s[last..whole.start()].to_owned();
+                        output.push((Event::Text(before.into()), range.clone()));
+                    }
+                    let open = format!("<a class=\"xref\" href=\"#{}\">", anchor(label));
+                    output.push((Event::InlineHtml(open.into()), range.clone()));
+                    output.push((Event::Text(text.into()), range.clone()));
+                    output.push((Event::InlineHtml("</a>".into()), range.clone()));
+                    last = whole.end();
+                }
+                if last < s.len() {
+                    let after = s[last..].to_owned();
+                    output.push((Event::Text(after.into()), range));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        output.push((event, range));
+    }
+
+    output
+}
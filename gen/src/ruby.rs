@@ -0,0 +1,149 @@
+//! Ruby annotations, written as `{漢字|かん|じ}` (one reading per character) or
+//! `{東京|とうきょう}` (one reading for the whole base text). Shared by post content and titles.
+
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{Event, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use regex::Regex;
+
+static RUBY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{([^{}|\s](?:[^{}|]*[^{}|\s])?)((?:\|[^{}|]*)+)\}").unwrap());
+
+pub enum Segment<'a> {
+    Text(&'a str),
+    /// Pairs of base text and reading. Either a single pair, or one pair per base character.
+    Ruby(Vec<(&'a str, &'a str)>),
+}
+
+pub fn parse(s: &str) -> anyhow::Result<Vec<Segment<'_>>> {
+    let mut segments = Vec::new();
+    let mut last = 0;
+    for cap in RUBY_RE.captures_iter(s) {
+        let whole = cap.get(0).unwrap();
+        if last < whole.start() {
+            segments.push(Segment::Text(&s[last..whole.start()]));
+        }
+        last = whole.end();
+
+        let base = cap.get(1).unwrap().as_str();
+        let readings: Vec<_> = cap.get(2).unwrap().as_str()[1..].split('|').collect();
+        let pairs = if readings.len() == 1 {
+            vec![(base, readings[0])]
+        } else if readings.len() == base.chars().count() {
+            base.char_indices()
+                .map(|(i, c)| &base[i..i + c.len_utf8()])
+                .zip(readings)
+                .collect()
+        } else {
+            return Err(anyhow::anyhow!(
+                "Ruby {} has {} readings for {} characters",
+                whole.as_str(),
+                readings.len(),
+                base.chars().count()
+            ));
+        };
+        segments.push(Segment::Ruby(pairs));
+    }
+    if last < s.len() {
+        segments.push(Segment::Text(&s[last..]));
+    }
+    Ok(segments)
+}
+
+/// Strips annotations, keeping only the base text
+pub fn base_text(s: &str) -> anyhow::Result<String> {
+    Ok(parse(s)?
+        .into_iter()
+        .map(|seg| match seg {
+            Segment::Text(t) => t.to_owned(),
+            Segment::Ruby(pairs) => pairs.into_iter().map(|(base, _)| base).collect(),
+        })
+        .collect())
+}
+
+/// Replaces annotated text with its reading, e.g. for sorting Japanese by pronunciation
+pub fn reading_text(s: &str) -> anyhow::Result<String> {
+    Ok(parse(s)?
+        .into_iter()
+        .map(|seg| match seg {
+            Segment::Text(t) => t.to_owned(),
+            Segment::Ruby(pairs) => pairs.into_iter().map(|(_, reading)| reading).collect(),
+        })
+        .collect())
+}
+
+fn push_ruby<'a>(
+    output: &mut Vec<(Event<'a>, Range<usize>)>,
+    pairs: Vec<(&str, &str)>,
+    range: &Range<usize>,
+) {
+    output.push((Event::InlineHtml("<ruby>".into()), range.clone()));
+    for (base, reading) in pairs {
+        // Readings are kept out of text events, so the plaintext only contains the base text
+        let mut rt = String::from("<rp>(</rp><rt>");
+        escape_html(&mut rt, reading).unwrap();
+        rt.push_str("</rt><rp>)</rp>");
+        output.push((Event::Text(base.to_owned().into()), range.clone()));
+        output.push((Event::InlineHtml(rt.into()), range.clone()));
+    }
+    output.push((Event::InlineHtml("</ruby>".into()), range.clone()));
+}
+
+/// Renders ruby annotations in text into `<ruby>` elements.
+///
+/// Adjacent text events are merged beforehand, since e.g. escaped pipes within tables
+/// produce separate events. Code blocks and image alt texts are left untouched.
+pub fn transform<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    report: impl Fn(usize, anyhow::Error),
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut output: Vec<(Event<'a>, Range<usize>)> = Vec::with_capacity(events.len());
+    let mut literal_depth = 0;
+    let mut pending: Option<(String, Range<usize>)> = None;
+
+    let flush = |pending: &mut Option<(String, Range<usize>)>,
+                 output: &mut Vec<(Event<'a>, Range<usize>)>| {
+        let Some((text, range)) = pending.take() else {
+            return;
+        };
+        let segments = match parse(&text) {
+            Ok(segments) => segments,
+            Err(e) => {
+                report(range.start, e);
+                return;
+            }
+        };
+        for seg in segments {
+            match seg {
+                Segment::Text(t) => output.push((Event::Text(t.to_owned().into()), range.clone())),
+                Segment::Ruby(pairs) => push_ruby(output, pairs, &range),
+            }
+        }
+    };
+
+    for (event, range) in events {
+        match event {
+            Event::Text(s) if literal_depth == 0 => match pending {
+                Some((ref mut text, ref mut r)) => {
+                    text.push_str(&s);
+                    r.end = range.end;
+                }
+                None => pending = Some((s.to_string(), range)),
+            },
+            event => {
+                flush(&mut pending, &mut output);
+                match event {
+                    Event::Start(Tag::CodeBlock(_) | Tag::Image { .. }) => literal_depth += 1,
+                    Event::End(TagEnd::CodeBlock | TagEnd::Image) => literal_depth -= 1,
+                    _ => {}
+                }
+                output.push((event, range));
+            }
+        }
+    }
+    flush(&mut pending, &mut output);
+
+    output
+}
@@ -3,7 +3,7 @@
 set -e
 
 cd gen
-# cargo run -- -p ../content -t ../fonts/SourceHanSerifSC-VF.otf -o ../web/src/assets/data.json --wght 900 --feed ../web/public/feed.xml --feed-cfg ./feed-cfg.json --subset-font ../web/src/assets/subset.woff2
+# cargo run -- -p ../content -t ../fonts/SourceHanSerifSC-VF.otf -o ../web/src/assets/data.json --wght 900 --feed ../web/public/feed.xml --feed-cfg ./feed-cfg.json --subset-font ../web/src/assets/subset.woff2 --assets ../web/public/assets --glossary ../web/src/assets/glossary.json
 
 cd ../web
 rm -rf dist
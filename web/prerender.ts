@@ -1,4 +1,3 @@
-import path from "node:path";
 import fs from "node:fs/promises";
 
 type Bootstrap = (
@@ -9,11 +8,13 @@ type Bootstrap = (
 const {
   bootstrap,
   listTags,
+  listPosts,
   reset,
   // @ts-ignore
 } = (await import("./dist/server/main.js")) as {
   bootstrap: Bootstrap;
   listTags: () => Promise<string[]>;
+  listPosts: () => Promise<string[]>;
   reset: () => void;
 };
 
@@ -71,12 +72,9 @@ async function work() {
   await renderPath("/about");
   await renderPath("/tags");
 
-  // Render all posts
-  for (const post of await fs.readdir("../content")) {
-    const filename = path.basename(post, ".md");
-    const [_, slug] = filename.match(/^\d{4}-\d{2}-\d{2}-(.*)$/)!;
-    await renderPath(`/post/${slug}`);
-  }
+  // Render all posts in the generated data, which excludes drafts and scheduled posts
+  const posts = await listPosts();
+  for (const post of posts) await renderPath(`/post/${post}`);
 
   // Render all tags
   const tags = await listTags();
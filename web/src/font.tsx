@@ -16,6 +16,9 @@ export type WidthLine = {
   line: GroupResp[];
 };
 
+// Keep in sync with .title .ruby in style.css
+const RUBY_SCALE = 0.5;
+
 // Render flow: render gives Line[] and RenderDimensions
 // Compare Line[] with rendered lines to see if they are the same
 // First render call materialize
@@ -56,6 +59,38 @@ export function materialize(
         return glyph;
       });
 
+      // Annotations are centered above their base characters
+      const rubies = grp.ruby.flatMap((ruby) => {
+        const baseStart = grp.chars
+          .slice(0, ruby.start)
+          .reduce((acc, chr) => acc + chr.hadv, 0);
+        const baseWidth = grp.chars
+          .slice(ruby.start, ruby.end)
+          .reduce((acc, chr) => acc + chr.hadv, 0);
+        let rubyAccum =
+          baseStart + (baseWidth - ruby.hadv * RUBY_SCALE) / 2;
+        return ruby.chars.map((chr) => {
+          const glyph = (
+            <g
+              class="glyph ruby"
+              style={{
+                "--in-grp-xdiff": (rubyAccum / dim.unitsPerEm).toString(),
+              }}
+            >
+              {chr.components.map((comp) => (
+                <path
+                  d={comp[0]}
+                  data-dir-x={comp[1][0]}
+                  data-dir-y={comp[1][1]}
+                ></path>
+              ))}
+            </g>
+          );
+          rubyAccum += chr.hadv * RUBY_SCALE;
+          return glyph;
+        });
+      });
+
       const hoverX = randomWithin(-1, 1);
       const hoverY = randomWithin(-1, 1);
 
@@ -74,6 +109,7 @@ export function materialize(
           data-text={grp.text}
         >
           {chrs}
+          {rubies}
         </g>
       );
 
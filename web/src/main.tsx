@@ -9,6 +9,7 @@ import {
   randomWithin,
   Debouncer,
   sliceDesc,
+  maskSpoilers,
 } from "./utils";
 import {
   render as renderLine,
@@ -94,7 +95,7 @@ type TagData = {
 function tags(posts: PostData[]): TagData[] {
   const tags = new Map<string, TagData>();
   for (const post of posts) {
-    if (post.metadata.hidden) continue;
+    if (post.metadata.visibility !== "public") continue;
 
     const time = Temporal.Instant.from(
       post.metadata.update_time ?? post.metadata.publish_time,
@@ -122,6 +123,11 @@ export async function listTags(): Promise<string[]> {
   return tags(data).map((e) => e.name);
 }
 
+export async function listPosts(): Promise<string[]> {
+  const data = await getData();
+  return Array.from(new Set(data.map((e) => e.metadata.id)));
+}
+
 function registerDOM(key: string, value: any) {
   document.getElementById(key)!.replaceWith(value);
 }
@@ -156,6 +162,17 @@ export async function bootstrap(
   observer.observe(sentinel);
 
   window.addEventListener("click", (e) => {
+    // Reveal spoilers, without following links within
+    const spoiler =
+      e.target instanceof Element
+        ? e.target.closest(".spoiler:not(.revealed)")
+        : null;
+    if (spoiler) {
+      spoiler.classList.add("revealed");
+      e.preventDefault();
+      return;
+    }
+
     // Check if is internal URL
     let link = getLinkInAnscenstor(e.target);
 
@@ -319,7 +336,7 @@ async function transitionRender(
   // TODO: hide list during debounce, match with transition duration
   if (state.ty === "Home")
     rendered = new List(
-      data.filter((e) => !e.metadata.hidden),
+      data.filter((e) => e.metadata.visibility === "public"),
       register,
     );
   else if (state.ty === "Search") {
@@ -332,13 +349,14 @@ async function transitionRender(
       title = post.metadata.title + " | 分层 - Layered";
       backlink = import.meta.env.VITE_BASE + "/post/" + slug;
       rendered = new Post(post, register);
-      desc = sliceDesc(post.plain, 300);
+      desc = sliceDesc(maskSpoilers(post.plain, post.spoilers), 300);
       img = post.metadata.img;
     }
   } else if (state.ty === "Tag") {
     const tag = state.tag;
     const filtered = data.filter(
-      (e) => !e.metadata.hidden && e.metadata.tags.includes(tag),
+      (e) =>
+        e.metadata.visibility === "public" && e.metadata.tags.includes(tag),
     );
     if (filtered.length > 0) {
       title = `标签：${tag} | 分层 - Layered`;
@@ -639,7 +657,7 @@ class List implements RenderedEntity {
     if (SSR) register!(":prerendered", "list");
 
     const entries = posts.map((p) => {
-      const preview = sliceDesc(p.plain);
+      const preview = sliceDesc(maskSpoilers(p.plain, p.spoilers));
       return ListCommon.renderEntry(p.metadata, [
         <div class="entry-preview">{preview}</div>,
       ]);
@@ -917,15 +935,23 @@ class Post implements RenderedEntity {
 
     function genBanner(metadata: Metadata): Element[] {
       const result = [];
-      if (metadata.hidden) {
+      if (metadata.visibility === "unlisted") {
         result.push(
           <div class="post-banner">
             <div class="post-banner-inner">
-              This post is hidden! But you can still share around the link if
+              This post is unlisted! But you can still share around the link if
               you'd like to.
             </div>
           </div>,
         );
+      } else if (metadata.visibility === "draft") {
+        result.push(
+          <div class="post-banner post-banner-amber">
+            <div class="post-banner-inner">
+              This post is a draft, and will not be published as-is.
+            </div>
+          </div>,
+        );
       }
 
       if (metadata.wip) {
@@ -938,6 +964,17 @@ class Post implements RenderedEntity {
         );
       }
 
+      if (metadata.spoilers) {
+        result.push(
+          <div class="post-banner post-banner-red">
+            <div class="post-banner-inner">
+              Spoilers ahead: {metadata.spoilers}. Spoilers are hidden until
+              clicked.
+            </div>
+          </div>,
+        );
+      }
+
       if (metadata.legacy) {
         const link = `https://legacy.meow.c-3.moe/${metadata.id}`;
         result.push(
@@ -1,5 +1,6 @@
 import { Metadata } from "../typings/Metadata";
 import { Post } from "../typings/Post";
+import { maskSpoilers } from "../utils";
 
 export type SearchPreviewSegment =
   | ["ellipsis"]
@@ -158,7 +159,7 @@ export default function perform(posts: Post[], query: string): SearchResult[] {
       return [
         {
           metadata: post.metadata,
-          plain: post.plain,
+          plain: maskSpoilers(post.plain, post.spoilers),
           preview: [],
           score: titleScore,
         },
@@ -215,7 +216,7 @@ export default function perform(posts: Post[], query: string): SearchResult[] {
     return [
       {
         metadata: post.metadata,
-        plain: post.plain,
+        plain: maskSpoilers(post.plain, post.spoilers),
         preview: regions,
         score: cur.score,
       },
@@ -70,6 +70,21 @@ export class Debouncer {
   }
 }
 
+export function maskSpoilers(
+  plain: string,
+  spoilers: [number, number][],
+): string {
+  // Ranges are in UTF-16 code units, so each masked code unit is replaced by exactly one
+  // character, keeping offsets into the text intact.
+  let masked = "";
+  let last = 0;
+  for (const [start, end] of spoilers) {
+    masked += plain.slice(last, start) + "█".repeat(end - start);
+    last = end;
+  }
+  return masked + plain.slice(last);
+}
+
 export function sliceDesc(plain: string, length: number = 150): string {
   // Generate description, breaks at white space (or after non-ASCII chars).
   // Each ASCII char counts as 1, non-ASCII as 2.